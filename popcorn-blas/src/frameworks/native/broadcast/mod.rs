@@ -13,7 +13,10 @@ pub fn try_new_broadcast<'a, T: 'a>(shape_a: &[usize],
                                     b: &'a [T],
                                     chop: usize) -> Result<(Vec<usize>, DenseBroadcastIter<'a, T>, DenseBroadcastIter<'a, T>), Error> {
   if !compatible(shape_a, shape_b) {
-    return Err(Error::InvalidBroadcast)
+    return Err(Error::ShapeMismatch {
+      a: shape_a.to_vec(),
+      b: shape_b.to_vec()
+    })
   }
 
   let mut bshape = target_shape(shape_a, shape_b);
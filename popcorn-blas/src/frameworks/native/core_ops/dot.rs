@@ -1,13 +1,85 @@
-use blas_sys::c::cblas_sdot;
+use blas_sys::c::{cblas_sdot, cblas_cdotu_sub, cblas_cdotc_sub, cblas_zdotu_sub, cblas_zdotc_sub};
+use popcorn::{bf16, f16, Complex32, Complex64};
+use std::cmp;
 
 pub trait Dot where Self: Sized {
+  /// Unconjugated dot product, `dotu` for complex values.
   fn dot(a: &[Self], b: &[Self]) -> Self;
+
+  /// Dot product with `a` conjugated. Real values are their own
+  /// conjugate, so this is `dot` unless the type is complex.
+  fn dotc(a: &[Self], b: &[Self]) -> Self { Self::dot(a, b) }
+}
+
+// Number of elements BLAS may read from both slices. Callers pass
+// rows of equal length, anything else is a bug, but BLAS must never
+// be told to read past the end of the shorter one
+fn blas_len<T>(a: &[T], b: &[T]) -> i32 {
+  debug_assert_eq!(a.len(), b.len());
+  cmp::min(a.len(), b.len()) as i32
 }
 
 impl Dot for f32 {
   fn dot(a: &[Self], b: &[Self]) -> Self {
     unsafe {
-      cblas_sdot(a.len() as i32, a.as_ptr(), 1, b.as_ptr(), 1)
+      cblas_sdot(blas_len(a, b), a.as_ptr(), 1, b.as_ptr(), 1)
+    }
+  }
+}
+
+// BLAS has no half precision routines, so products are summed in f32
+// to keep long vectors from losing precision
+fn dot_f32<T, F: Fn(&T) -> f32>(a: &[T], b: &[T], to_f32: F) -> f32 {
+  debug_assert_eq!(a.len(), b.len());
+  a.iter().zip(b).fold(0.0, |sum, (a, b)| sum + to_f32(a) * to_f32(b))
+}
+
+impl Dot for f16 {
+  fn dot(a: &[Self], b: &[Self]) -> Self {
+    f16::from_f32(dot_f32(a, b, |v| v.to_f32()))
+  }
+}
+
+impl Dot for bf16 {
+  fn dot(a: &[Self], b: &[Self]) -> Self {
+    bf16::from_f32(dot_f32(a, b, |v| v.to_f32()))
+  }
+}
+
+// Complex values are laid out as a pair of floats, which is what
+// BLAS expects
+impl Dot for Complex32 {
+  fn dot(a: &[Self], b: &[Self]) -> Self {
+    let mut r = Complex32::new(0.0, 0.0);
+    unsafe {
+      cblas_cdotu_sub(blas_len(a, b), a.as_ptr() as *const _, 1, b.as_ptr() as *const _, 1, &mut r as *mut Complex32 as *mut _);
+    }
+    r
+  }
+
+  fn dotc(a: &[Self], b: &[Self]) -> Self {
+    let mut r = Complex32::new(0.0, 0.0);
+    unsafe {
+      cblas_cdotc_sub(blas_len(a, b), a.as_ptr() as *const _, 1, b.as_ptr() as *const _, 1, &mut r as *mut Complex32 as *mut _);
+    }
+    r
+  }
+}
+
+impl Dot for Complex64 {
+  fn dot(a: &[Self], b: &[Self]) -> Self {
+    let mut r = Complex64::new(0.0, 0.0);
+    unsafe {
+      cblas_zdotu_sub(blas_len(a, b), a.as_ptr() as *const _, 1, b.as_ptr() as *const _, 1, &mut r as *mut Complex64 as *mut _);
+    }
+    r
+  }
+
+  fn dotc(a: &[Self], b: &[Self]) -> Self {
+    let mut r = Complex64::new(0.0, 0.0);
+    unsafe {
+      cblas_zdotc_sub(blas_len(a, b), a.as_ptr() as *const _, 1, b.as_ptr() as *const _, 1, &mut r as *mut Complex64 as *mut _);
     }
+    r
   }
 }
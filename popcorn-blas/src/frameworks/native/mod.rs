@@ -1,64 +1,286 @@
 pub mod broadcast;
 pub mod core_ops;
 
-use popcorn::frameworks::native::Framework;
+use popcorn::frameworks::native::{Device, Framework};
 use popcorn::backend::Backend;
 use operation::*;
-use futures::Future;
-use popcorn::buffer::{Buffer, BufferDevice, Error};
+use futures::{Future, IntoFuture};
+use popcorn::buffer::{Buffer, BufferView, BufferDevice, Error};
+use popcorn::cancel::CancelToken;
+use popcorn::profile::Timer;
+use popcorn::tensor::{Tensor, TensorView};
+use std::cmp;
 use std::fmt;
+use std::iter;
+use std::mem;
+
+// Outputs computed between two checks of the cancel token
+const CANCEL_CHUNK: usize = 1024;
 
 pub use self::core_ops::*;
 
 impl<B: Backend<Framework>, T: Dot + fmt::Debug + Sync + Copy + Sized + Send + 'static> DotOperation<T> for B {
   fn bcast_dot(&self,
-               shape_a: Buffer<usize>,
-               a: Buffer<T>,
-               shape_b: Buffer<usize>,
-               b: Buffer<T>,
+               shape_a: BufferView<usize>,
+               a: BufferView<T>,
+               shape_b: BufferView<usize>,
+               b: BufferView<T>,
                shape_c: Buffer<usize>,
                c: Buffer<T>) ->
-    Box<Future<Item=(Buffer<usize>, Buffer<T>,
-                     Buffer<usize>, Buffer<T>,
-                     Buffer<usize>, Buffer<T>), Error=Error>> {
-      // Step 1. Sync all input buffers to the required device
-      let bdev = BufferDevice::Native(self.device().clone());
-      let ar = shape_a.sync(&bdev).join(a.sync(&bdev));
-      let br = shape_b.sync(&bdev).join(b.sync(&bdev));
-      let cr = shape_c.sync(&bdev).join(c.sync(&bdev));
-
-
-      // Step 2. Convert all memory to native memory and execute the
-      //   broadcasted dot operation on the cpu pool
-      let dev = self.device().clone();
-      let pool = self.device().pool().clone();
-      Box::new(ar.join(br).join(cr).and_then(move |(((shape_a, a), (shape_b, b)), (mut shape_c, mut c))| {
-        pool.spawn_fn(move || {
-          {
-            let n_shape_a: &[usize] = try!(try!(shape_a.native_memory(&dev)).try_as_slice());
-            let n_a: &[T] = try!(try!(a.native_memory(&dev)).try_as_slice());
-            let n_shape_b: &[usize] = try!(try!(shape_b.native_memory(&dev)).try_as_slice());
-            let n_b: &[T] = try!(try!(b.native_memory(&dev)).try_as_slice());
-            let n_shape_c: &mut [usize] = try!(try!(shape_c.native_memory_mut(&dev)).try_as_mut_slice());
-            let n_c: &mut [T] = try!(try!(c.native_memory_mut(&dev)).try_as_mut_slice());
-
-            let (mut bshape, iter_a, iter_b) = try!(broadcast::try_new_broadcast(n_shape_a, n_a, n_shape_b, n_b, 1));
-            bshape.pop();
-
-            let r_iter = iter_a.zip(iter_b).map(|(a, b)| T::dot(a, b));
-            for (v1, v2) in bshape.iter().zip(n_shape_c.iter_mut()) {
-              *v2 = *v1;
-            }
-
-            for(v1, v2) in r_iter.zip(n_c.iter_mut()) {
-              *v2 = v1;
-            }
+    Box<Future<Item=(Buffer<usize>, Buffer<T>), Error=Error>> {
+      Box::new(self.bcast_dot_with(shape_a, a, shape_b, b, shape_c, c, &CancelToken::new()).
+               map_err(|(err, _, _)| err))
+    }
+
+  fn bcast_dot_with(&self,
+                    shape_a: BufferView<usize>,
+                    a: BufferView<T>,
+                    shape_b: BufferView<usize>,
+                    b: BufferView<T>,
+                    shape_c: Buffer<usize>,
+                    c: Buffer<T>,
+                    token: &CancelToken) ->
+    Box<Future<Item=(Buffer<usize>, Buffer<T>), Error=(Error, Buffer<usize>, Buffer<T>)>> {
+      bcast_with(self, shape_a, a, shape_b, b, shape_c, c, T::dot, token)
+    }
+
+  fn bcast_dotc(&self,
+                shape_a: BufferView<usize>,
+                a: BufferView<T>,
+                shape_b: BufferView<usize>,
+                b: BufferView<T>,
+                shape_c: Buffer<usize>,
+                c: Buffer<T>) ->
+    Box<Future<Item=(Buffer<usize>, Buffer<T>), Error=Error>> {
+      Box::new(bcast_with(self, shape_a, a, shape_b, b, shape_c, c, T::dotc, &CancelToken::new()).
+               map_err(|(err, _, _)| err))
+    }
+
+  fn tensor_dot<A: Into<TensorView<T>>, BV: Into<TensorView<T>>>(&self,
+                                                                 a: A,
+                                                                 b: BV,
+                                                                 c: Buffer<T>) ->
+    Box<Future<Item=Tensor<T>, Error=Error>> {
+      Box::new(self.tensor_dot_with(a, b, c, &CancelToken::new()).map_err(|(err, _)| err))
+    }
+
+  fn tensor_dot_with<A: Into<TensorView<T>>, BV: Into<TensorView<T>>>(&self,
+                                                                      a: A,
+                                                                      b: BV,
+                                                                      c: Buffer<T>,
+                                                                      token: &CancelToken) ->
+    Box<Future<Item=Tensor<T>, Error=(Error, Buffer<T>)>> {
+      tensor_with(self, a.into(), b.into(), c, T::dot, token)
+    }
+
+  fn tensor_dotc<A: Into<TensorView<T>>, BV: Into<TensorView<T>>>(&self,
+                                                                  a: A,
+                                                                  b: BV,
+                                                                  c: Buffer<T>) ->
+    Box<Future<Item=Tensor<T>, Error=Error>> {
+      Box::new(tensor_with(self, a.into(), b.into(), c, T::dotc, &CancelToken::new()).map_err(|(err, _)| err))
+    }
+}
+
+// Runs the broadcasted product `kernel` over buffers holding their shapes
+fn bcast_with<B, T>(backend: &B,
+                    shape_a: BufferView<usize>,
+                    a: BufferView<T>,
+                    shape_b: BufferView<usize>,
+                    b: BufferView<T>,
+                    shape_c: Buffer<usize>,
+                    c: Buffer<T>,
+                    kernel: fn(&[T], &[T]) -> T,
+                    token: &CancelToken) ->
+  Box<Future<Item=(Buffer<usize>, Buffer<T>), Error=(Error, Buffer<usize>, Buffer<T>)>>
+  where B: Backend<Framework>, T: Sync + Copy + Send + 'static {
+    // Step 1. Sync all buffers to the required device
+    //   Shared inputs have to be up to date on the device already. The
+    //   outputs are synced one after the other and before the inputs, so
+    //   whatever fails they are still around to go back with the error
+    let bdev = BufferDevice::Native(backend.device().clone());
+    let ar = shape_a.sync(&bdev).join(a.sync(&bdev));
+    let br = shape_b.sync(&bdev).join(b.sync(&bdev));
+    let inputs = ar.join(br);
+    let c_dev = bdev.clone();
+    let c_token = token.clone();
+    let outputs = shape_c.sync_with(&bdev, token).then(move |res| -> Box<Future<Item=_, Error=_>> {
+      match res {
+        Ok(shape_c) => Box::new(c.sync_with(&c_dev, &c_token).then(move |res| match res {
+          Ok(c) => Ok((shape_c, c)),
+          Err((err, c)) => Err((err, shape_c, c))
+        })),
+        Err((err, shape_c)) => Box::new(Err((err, shape_c, c)).into_future())
+      }
+    });
+    let synced = outputs.and_then(move |(shape_c, c)| inputs.then(move |res| match res {
+      Ok(inputs) => Ok((inputs, shape_c, c)),
+      Err(err) => Err((err, shape_c, c))
+    }));
+
+    // Step 2. Convert all memory to native memory and execute the
+    //   broadcasted dot operation on the cpu pool
+    let dev = backend.device().clone();
+    let pool = backend.device().pool().clone();
+    let token = token.clone();
+    Box::new(synced.and_then(move |(((shape_a, a), (shape_b, b)), mut shape_c, mut c)| {
+      let mut timer = dev.timer("bcast_dot", (a.len() + b.len() + c.len()) * mem::size_of::<T>());
+      pool.spawn_fn(move || {
+        timer.start();
+        let res = bcast_kernel(&dev, &shape_a, &a, &shape_b, &b, &mut shape_c, &mut c, kernel, &token, &mut timer);
+
+        // The outputs only count as written once the whole kernel went
+        //   through
+        match res {
+          Ok(()) => {
+            shape_c.mark_modified(&bdev);
+            c.mark_modified(&bdev);
+            timer.finish();
+            Ok((shape_c, c))
+          },
+          Err(err) => {
+            shape_c.mark_stale(&bdev);
+            c.mark_stale(&bdev);
+            Err((err, shape_c, c))
+          }
+        }
+      })
+    }))
+  }
+
+fn bcast_kernel<T: Copy + Send + 'static>(dev: &Device,
+                                          shape_a: &BufferView<usize>,
+                                          a: &BufferView<T>,
+                                          shape_b: &BufferView<usize>,
+                                          b: &BufferView<T>,
+                                          shape_c: &mut Buffer<usize>,
+                                          c: &mut Buffer<T>,
+                                          kernel: fn(&[T], &[T]) -> T,
+                                          token: &CancelToken,
+                                          timer: &mut Timer) -> Result<(), Error> {
+  // Work queued behind other kernels may be cancelled before it starts
+  try!(token.check());
+
+  let n_shape_a: &[usize] = try!(shape_a.native_slice(dev));
+  let n_a: &[T] = try!(a.native_slice(dev));
+  let n_shape_b: &[usize] = try!(shape_b.native_slice(dev));
+  let n_b: &[T] = try!(b.native_slice(dev));
+  let n_shape_c: &mut [usize] = try!(try!(shape_c.native_memory_mut_unmarked(dev)).try_as_mut_slice());
+  let n_c: &mut [T] = try!(try!(c.native_memory_mut_unmarked(dev)).try_as_mut_slice());
+
+  let bshape = try!(dot_into(n_shape_a, n_a, n_shape_b, n_b, n_c, kernel, token));
+  timer.set_flops(dot_flops(n_c.len(), n_shape_a));
+  if n_shape_c.len() != bshape.len() {
+    return Err(Error::SizeMismatch { expected: bshape.len(), actual: n_shape_c.len() })
+  }
+  n_shape_c.copy_from_slice(&bshape);
+
+  Ok(())
+}
+
+fn tensor_with<B, T>(backend: &B,
+                     a: TensorView<T>,
+                     b: TensorView<T>,
+                     c: Buffer<T>,
+                     kernel: fn(&[T], &[T]) -> T,
+                     token: &CancelToken) ->
+  Box<Future<Item=Tensor<T>, Error=(Error, Buffer<T>)>>
+  where B: Backend<Framework>, T: Sync + Copy + Send + 'static {
+    let bdev = BufferDevice::Native(backend.device().clone());
+    let inputs = a.sync(&bdev).join(b.sync(&bdev));
+    let synced = c.sync_with(&bdev, token).and_then(move |c| inputs.then(move |res| match res {
+      Ok(inputs) => Ok((inputs, c)),
+      Err(err) => Err((err, c))
+    }));
+
+    let dev = backend.device().clone();
+    let pool = backend.device().pool().clone();
+    let token = token.clone();
+    Box::new(synced.and_then(move |((a, b), mut c)| {
+      let bytes = (a.view().len() + b.view().len() + c.len()) * mem::size_of::<T>();
+      let mut timer = dev.timer("tensor_dot", bytes);
+      pool.spawn_fn(move || {
+        timer.start();
+        match tensor_kernel(&dev, &a, &b, &mut c, kernel, &token, &mut timer) {
+          Ok(bshape) => {
+            c.mark_modified(&bdev);
+            timer.finish();
+            // `dot_into` made sure `c` holds exactly the output shape
+            Ok(Tensor::new(c, bshape).expect("output sized by dot_into"))
+          },
+          Err(err) => {
+            c.mark_stale(&bdev);
+            Err((err, c))
           }
+        }
+      })
+    }))
+  }
+
+fn tensor_kernel<T: Copy + Send + 'static>(dev: &Device,
+                                           a: &TensorView<T>,
+                                           b: &TensorView<T>,
+                                           c: &mut Buffer<T>,
+                                           kernel: fn(&[T], &[T]) -> T,
+                                           token: &CancelToken,
+                                           timer: &mut Timer) -> Result<Vec<usize>, Error> {
+  try!(token.check());
+
+  let n_a: &[T] = try!(a.view().native_slice(dev));
+  let n_b: &[T] = try!(b.view().native_slice(dev));
+  let n_c: &mut [T] = try!(try!(c.native_memory_mut_unmarked(dev)).try_as_mut_slice());
 
-          Ok((shape_a, a,
-              shape_b, b,
-              shape_c, c))
-        })
-      }))
+  let bshape = try!(dot_into(a.dims(), n_a, b.dims(), n_b, n_c, kernel, token));
+  timer.set_flops(dot_flops(n_c.len(), a.dims()));
+  Ok(bshape)
+}
+
+// A multiply and an add per element of the last dimension, for every
+// output
+fn dot_flops(len_c: usize, shape_a: &[usize]) -> u64 {
+  2 * (len_c * shape_a.last().cloned().unwrap_or(1)) as u64
+}
+
+// Broadcasts `a` against `b`, writes the products `kernel` computes over
+// the last dimension into `c` and returns the shape of the result. Stops
+// between chunks of `c` once `token` is cancelled, the chunks before stay
+// written.
+fn dot_into<T: Copy>(shape_a: &[usize],
+                     a: &[T],
+                     shape_b: &[usize],
+                     b: &[T],
+                     c: &mut [T],
+                     kernel: fn(&[T], &[T]) -> T,
+                     token: &CancelToken) -> Result<Vec<usize>, Error> {
+  // Only the leading dimensions broadcast, a contracted dimension of 1
+  //   must not be stretched to fit the other one
+  if shape_a.last() != shape_b.last() {
+    return Err(Error::ShapeMismatch { a: shape_a.to_vec(), b: shape_b.to_vec() })
+  }
+
+  // Broadcasting chops the last dimension off the shape already. Both
+  //   shapes are padded to the same rank plus a leading 1, so vectors
+  //   still keep a dimension to iterate over after the chop
+  let ndim = cmp::max(shape_a.len(), shape_b.len()) + 1;
+  let pad = |shape: &[usize]| -> Vec<usize> {
+    iter::repeat(1).take(ndim - shape.len()).chain(shape.iter().cloned()).collect()
+  };
+  let (mut bshape, iter_a, iter_b) = try!(broadcast::try_new_broadcast(&pad(shape_a), a, &pad(shape_b), b, 1));
+  bshape.remove(0);
+
+  let len: usize = bshape.iter().product();
+  if c.len() != len {
+    return Err(Error::SizeMismatch { expected: len, actual: c.len() })
+  }
+
+  let mut r_iter = iter_a.zip(iter_b).map(|(a, b)| kernel(a, b));
+  for chunk in c.chunks_mut(CANCEL_CHUNK) {
+    try!(token.check());
+    // The chunk goes first so zip never pulls a product it cannot store
+    for (v2, v1) in chunk.iter_mut().zip(r_iter.by_ref()) {
+      *v2 = v1;
     }
+  }
+
+  Ok(bshape)
 }
@@ -21,20 +21,235 @@ mod native_test {
 
     let shape_vec = vec![1, 4];
 
-    let shape_a: Buffer<usize> = Buffer::new(backend.device(), 2).unwrap().sync_from_vec(shape_vec.clone(), backend.device()).wait().unwrap();
-    let a: Buffer<f32> = Buffer::new(backend.device(), 4).unwrap().sync_from_vec(vec![1.0, 2.0, 3.0, 4.0], backend.device()).wait().unwrap();
-    let shape_b: Buffer<usize> = Buffer::new(backend.device(), 2).unwrap().sync_from_vec(shape_vec.clone(), backend.device()).wait().unwrap();
-    let b: Buffer<f32> = Buffer::new(backend.device(), 4).unwrap().sync_from_vec(vec![2.0, 2.0, 2.0, 2.0], backend.device()).wait().unwrap();
-    let shape_c: Buffer<usize> = Buffer::new(backend.device(), 0).unwrap();
+    let shape_a: ReadBuffer<usize> = Buffer::new(backend.device(), 2).unwrap().sync_from_vec(shape_vec.clone(), backend.device()).wait().unwrap().share();
+    let a: ReadBuffer<f32> = Buffer::new(backend.device(), 4).unwrap().sync_from_vec(vec![1.0, 2.0, 3.0, 4.0], backend.device()).wait().unwrap().share();
+    let shape_b: ReadBuffer<usize> = Buffer::new(backend.device(), 2).unwrap().sync_from_vec(shape_vec.clone(), backend.device()).wait().unwrap().share();
+    let b: ReadBuffer<f32> = Buffer::new(backend.device(), 4).unwrap().sync_from_vec(vec![2.0, 2.0, 2.0, 2.0], backend.device()).wait().unwrap().share();
+    let shape_c: Buffer<usize> = Buffer::new(backend.device(), 1).unwrap();
     let c: Buffer<f32> = Buffer::new(backend.device(), 1).unwrap();
+    let shape_d: Buffer<usize> = Buffer::new(backend.device(), 1).unwrap();
+    let d: Buffer<f32> = Buffer::new(backend.device(), 1).unwrap();
 
-    let (_shape_aa, _aa, _shape_bb, _bb, shape_c, c) = backend.bcast_dot(shape_a, a,
-                                                                         shape_b, b,
-                                                                         shape_c, c).wait().unwrap();
+    // Both operations read the same inputs at the same time, the second
+    // one through views of the same buffers
+    let fc = backend.bcast_dot(shape_a.clone().into(), a.clone().into(),
+                               shape_b.clone().into(), b.clone().into(),
+                               shape_c, c);
+    let fd = backend.bcast_dot(shape_a.view(0, 2).unwrap(), a.view(0, 4).unwrap(),
+                               shape_b.view(0, 2).unwrap(), b.view(0, 4).unwrap(),
+                               shape_d, d);
+    let ((shape_c, c), (_shape_d, d)) = fc.join(fd).wait().unwrap();
 
     let shape_c_vec = shape_c.native_memory(backend.device()).unwrap().try_as_slice::<usize>().unwrap();
     let c_vec = c.native_memory(backend.device()).unwrap().try_as_slice::<f32>().unwrap();
     println!("Shape: {:?}", &shape_c_vec);
     println!("Contents: {:?}", &c_vec);
+    assert_eq!(shape_c_vec, &[1]);
+    assert_eq!(c_vec, &[20.0]);
+    assert_eq!(c_vec, d.native_memory(backend.device()).unwrap().try_as_slice::<f32>().unwrap());
+  }
+
+  #[test]
+  fn tensor_dot_test() {
+    let backend = popcorn::frameworks::native::Backend::default();
+
+    let a = Tensor::from_vec(backend.device(), vec![1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0], vec![2, 3]).unwrap();
+    let b = Tensor::from_vec(backend.device(), vec![2.0f32, 2.0, 2.0], vec![3]).unwrap();
+    let c: Buffer<f32> = Buffer::new(backend.device(), 2).unwrap();
+
+    let c = backend.tensor_dot(a, b, c).wait().unwrap();
+    assert_eq!(c.dims(), &[2]);
+    assert_eq!(c.buffer().native_memory(backend.device()).unwrap().try_as_slice::<f32>().unwrap(), &[12.0, 30.0]);
+
+    // Vectors give a single product without dimensions
+    let a = Tensor::from_vec(backend.device(), vec![1.0f32, 2.0, 3.0], vec![3]).unwrap();
+    let b = Tensor::from_vec(backend.device(), vec![4.0f32, 5.0, 6.0], vec![3]).unwrap();
+    let c: Buffer<f32> = Buffer::new(backend.device(), 1).unwrap();
+    let c = backend.tensor_dot(a, b, c).wait().unwrap();
+    assert_eq!(c.dims(), &[] as &[usize]);
+    assert_eq!(c.buffer().native_memory(backend.device()).unwrap().try_as_slice::<f32>().unwrap(), &[32.0]);
+
+    let a = Tensor::from_vec(backend.device(), vec![1.0f32, 2.0], vec![2]).unwrap();
+    let b = Tensor::from_vec(backend.device(), vec![1.0f32, 2.0, 3.0], vec![3]).unwrap();
+    let c: Buffer<f32> = Buffer::new(backend.device(), 1).unwrap();
+    assert!(backend.tensor_dot(a, b, c).wait().is_err());
+
+    // The contracted dimensions have to match, a 1 is not broadcast
+    let a = Tensor::from_vec(backend.device(), vec![1.0f32, 2.0, 3.0, 4.0], vec![4]).unwrap();
+    let b = Tensor::from_vec(backend.device(), vec![1.0f32], vec![1]).unwrap();
+    let c: Buffer<f32> = Buffer::new(backend.device(), 1).unwrap();
+    match backend.tensor_dot(a, b, c).wait() {
+      Err(popcorn::buffer::Error::ShapeMismatch { a, b }) => assert_eq!((a, b), (vec![4], vec![1])),
+      res => panic!("expected a shape mismatch, got {:?}", res.map(|c| c.dims().to_vec()))
+    }
+  }
+
+  #[test]
+  fn stale_input_dot_test() {
+    use popcorn::frameworks::native::Framework;
+
+    let backend = popcorn::frameworks::native::Backend::default();
+    let bdev = BufferDevice::from(backend.device());
+    let framework = Framework::new();
+    let other = framework.new_device(&framework.default_hardware()).unwrap();
+
+    // Inputs only written on another device are not synced for the caller
+    let a = Tensor::from_vec(&other, vec![1.0f32, 2.0], vec![2]).unwrap();
+    let b = Tensor::from_vec(backend.device(), vec![3.0f32, 4.0], vec![2]).unwrap().share();
+    let c: Buffer<f32> = Buffer::new(backend.device(), 1).unwrap();
+    match backend.tensor_dot(a, b.clone(), c).wait() {
+      Err(popcorn::buffer::Error::StaleCopy(dev)) => assert!(dev == bdev),
+      res => panic!("expected a stale copy, got {:?}", res.map(|c| c.dims().to_vec()))
+    }
+
+    // Syncing them before they are shared is up to the caller
+    let a = Tensor::from_vec(&other, vec![1.0f32, 2.0], vec![2]).unwrap();
+    let a = a.sync(&bdev).wait().unwrap().share();
+    let c: Buffer<f32> = Buffer::new(backend.device(), 1).unwrap();
+    let c = backend.tensor_dot(a, b, c).wait().unwrap();
+    assert_eq!(c.into_buffer().into_vec(backend.device()).wait().unwrap(), vec![11.0]);
+  }
+
+  #[test]
+  fn half_dot_test() {
+    let backend = popcorn::frameworks::native::Backend::default();
+
+    // Summing in f16 would get stuck at 2048, since 2048 + 1 rounds back
+    // to 2048
+    let mut a = vec![f16::from_f32(1.0); 4096];
+    a[0] = f16::from_f32(2048.0);
+    let ones = vec![f16::from_f32(1.0); 4096];
+
+    let a = Tensor::from_vec(backend.device(), a, vec![4096]).unwrap();
+    let b = Tensor::from_vec(backend.device(), ones, vec![4096]).unwrap();
+    let c: Buffer<f16> = Buffer::new(backend.device(), 1).unwrap();
+
+    let c = backend.tensor_dot(a, b, c).wait().unwrap();
+    let c = c.buffer().native_memory(backend.device()).unwrap().try_as_slice::<f16>().unwrap()[0];
+    assert_eq!(c.to_f32(), 6144.0);
+
+    // Two rows of 20000 products of 0.25 each. An f16 sum stops growing
+    // at 512, where the spacing is already 0.5
+    let a = Tensor::from_vec(backend.device(), vec![f16::from_f32(0.5); 40000], vec![2, 20000]).unwrap();
+    let b = Tensor::from_vec(backend.device(), vec![f16::from_f32(0.5); 20000], vec![20000]).unwrap();
+    let c: Buffer<f16> = Buffer::new(backend.device(), 2).unwrap();
+    let c = backend.tensor_dot(a, b, c).wait().unwrap();
+    assert_eq!(c.dims(), &[2]);
+    let c: Vec<f32> = c.buffer().native_memory(backend.device()).unwrap().try_as_slice::<f16>().unwrap().
+      iter().map(|v| v.to_f32()).collect();
+    assert_eq!(c, vec![5000.0, 5000.0]);
+
+    // A bf16 sum of ones gets stuck at 256
+    let a = Tensor::from_vec(backend.device(), vec![bf16::from_f32(1.0); 1000], vec![1000]).unwrap();
+    let b = Tensor::from_vec(backend.device(), vec![bf16::from_f32(1.0); 1000], vec![1000]).unwrap();
+    let c: Buffer<bf16> = Buffer::new(backend.device(), 1).unwrap();
+    let c = backend.tensor_dot(a, b, c).wait().unwrap();
+    let c = c.buffer().native_memory(backend.device()).unwrap().try_as_slice::<bf16>().unwrap()[0];
+    assert_eq!(c.to_f32(), 1000.0);
+  }
+
+  #[test]
+  fn complex_dot_test() {
+    let backend = popcorn::frameworks::native::Backend::default();
+
+    let a = vec![Complex32::new(1.0, 2.0), Complex32::new(0.0, 1.0)];
+    let b = vec![Complex32::new(3.0, 0.0), Complex32::new(2.0, 2.0)];
+    let a = Tensor::from_vec(backend.device(), a, vec![2]).unwrap();
+    let b = Tensor::from_vec(backend.device(), b, vec![2]).unwrap();
+    let a = a.share();
+    let b = b.share();
+
+    // (1+2i)*3 + i*(2+2i) = 1+8i and (1-2i)*3 - i*(2+2i) = 5-8i
+    let c: Buffer<Complex32> = Buffer::new(backend.device(), 1).unwrap();
+    let c = backend.tensor_dot(a.clone(), b.clone(), c).wait().unwrap();
+    let dot = c.buffer().native_memory(backend.device()).unwrap().try_as_slice::<Complex32>().unwrap()[0];
+    assert_eq!(dot, Complex32::new(1.0, 8.0));
+
+    let c: Buffer<Complex32> = Buffer::new(backend.device(), 1).unwrap();
+    let c = backend.tensor_dotc(a, b, c).wait().unwrap();
+    let dotc = c.buffer().native_memory(backend.device()).unwrap().try_as_slice::<Complex32>().unwrap()[0];
+    assert_eq!(dotc, Complex32::new(5.0, -8.0));
+    assert!(dot != dotc);
+
+    // Both rows of a against the same b, in double precision. The first
+    //   row gives (1+i)*(1-i) + 2*i = 2+2i, conjugated (1-i)*(1-i) + 2*i = 0
+    let a = vec![Complex64::new(1.0, 1.0), Complex64::new(2.0, 0.0),
+                 Complex64::new(0.0, 1.0), Complex64::new(1.0, 0.0)];
+    let b = vec![Complex64::new(1.0, -1.0), Complex64::new(0.0, 1.0)];
+    let a = Tensor::from_vec(backend.device(), a, vec![2, 2]).unwrap().share();
+    let b = Tensor::from_vec(backend.device(), b, vec![2]).unwrap().share();
+
+    let c: Buffer<Complex64> = Buffer::new(backend.device(), 2).unwrap();
+    let c = backend.tensor_dot(a.clone(), b.clone(), c).wait().unwrap();
+    let dot = c.buffer().native_memory(backend.device()).unwrap().try_as_slice::<Complex64>().unwrap().to_vec();
+    assert_eq!(dot, vec![Complex64::new(2.0, 2.0), Complex64::new(1.0, 2.0)]);
+
+    let c: Buffer<Complex64> = Buffer::new(backend.device(), 2).unwrap();
+    let c = backend.tensor_dotc(a, b, c).wait().unwrap();
+    let dotc = c.buffer().native_memory(backend.device()).unwrap().try_as_slice::<Complex64>().unwrap().to_vec();
+    assert_eq!(dotc, vec![Complex64::new(0.0, 0.0), Complex64::new(-1.0, 0.0)]);
+    assert!(dot != dotc);
+  }
+
+  #[test]
+  fn cancel_dot_test() {
+    let backend = popcorn::frameworks::native::Backend::default();
+
+    let a = Tensor::from_vec(backend.device(), vec![1.0f32; 4096], vec![4096, 1]).unwrap();
+    let b = Tensor::from_vec(backend.device(), vec![2.0f32], vec![1]).unwrap();
+    let a = a.share();
+    let b = b.share();
+    let c = Buffer::from_vec(backend.device(), vec![5.0f32; 4096]).unwrap();
+
+    // A token cancelled up front stops the sync of the output, so it
+    //   comes back with the error as it was
+    let token = CancelToken::new();
+    token.cancel();
+    let c = match backend.tensor_dot_with(a.clone(), b.clone(), c, &token).wait() {
+      Err((popcorn::buffer::Error::Cancelled, c)) => c,
+      Err((err, _)) => panic!("expected cancellation, got {:?}", err),
+      Ok(c) => panic!("expected cancellation, got {:?}", c.dims())
+    };
+    assert!(c.is_latest(&BufferDevice::from(backend.device())));
+    let (c, vec) = c.sync_to_vec(backend.device()).wait().unwrap();
+    assert_eq!(vec, vec![5.0f32; 4096]);
+
+    // The returned output can be used again
+    let c = backend.tensor_dot_with(a, b, c, &CancelToken::new()).wait().ok().unwrap();
+    assert_eq!(c.into_buffer().into_vec(backend.device()).wait().unwrap(), vec![2.0f32; 4096]);
+  }
+
+  #[test]
+  fn profile_dot_test() {
+    use std::sync::Arc;
+
+    let backend = popcorn::frameworks::native::Backend::default();
+    let collector = Arc::new(Collector::new());
+    backend.device().set_profiler(Some(collector.clone()));
+
+    let a = Tensor::from_vec(backend.device(), vec![1.0f32; 6], vec![2, 3]).unwrap();
+    let b = Tensor::from_vec(backend.device(), vec![2.0f32; 3], vec![3]).unwrap();
+    let c: Buffer<f32> = Buffer::new(backend.device(), 2).unwrap();
+    backend.tensor_dot(a, b, c).wait().unwrap();
+    backend.device().set_profiler(None);
+
+    // Two outputs over three elements each
+    let summary = collector.summary();
+    let op = summary.get("tensor_dot").unwrap();
+    assert_eq!(op.count, 1);
+    assert_eq!(op.flops, Some(12));
+    assert_eq!(op.bytes, 11 * 4);
+
+    // Kernels failing halfway are recorded as well
+    collector.clear();
+    backend.device().set_profiler(Some(collector.clone()));
+    let a = Tensor::from_vec(backend.device(), vec![1.0f32; 6], vec![2, 3]).unwrap();
+    let b = Tensor::from_vec(backend.device(), vec![2.0f32; 3], vec![3]).unwrap();
+    let c: Buffer<f32> = Buffer::new(backend.device(), 3).unwrap();
+    assert!(backend.tensor_dot(a, b, c).wait().is_err());
+    backend.device().set_profiler(None);
+
+    let spans = collector.spans();
+    assert_eq!(spans.iter().filter(|span| span.name == "tensor_dot").count(), 1);
   }
 }
@@ -1,15 +1,72 @@
 use futures::Future;
-use popcorn::buffer::{Buffer, Error};
+use popcorn::buffer::{Buffer, BufferView, Error};
+use popcorn::cancel::CancelToken;
+use popcorn::tensor::{Tensor, TensorView};
 
+/// Dot products over the last dimension of broadcasted inputs. Inputs
+/// are shared, so they cannot be synced here: the caller syncs them to
+/// the backend's device before sharing them, and an input that is out of
+/// date there fails the operation with `Error::StaleCopy`. Outputs are
+/// synced as needed.
 pub trait DotOperation<T: Copy + Send + 'static> {
   fn bcast_dot(&self,
-               shape_a: Buffer<usize>,
-               a: Buffer<T>,
-               shape_b: Buffer<usize>,
-               b: Buffer<T>,
+               shape_a: BufferView<usize>,
+               a: BufferView<T>,
+               shape_b: BufferView<usize>,
+               b: BufferView<T>,
                shape_c: Buffer<usize>,
                c: Buffer<T>) ->
-    Box<Future<Item=(Buffer<usize>, Buffer<T>, // A
-                     Buffer<usize>, Buffer<T>, // B
-                     Buffer<usize>, Buffer<T>), Error=Error>>; // Result
+    Box<Future<Item=(Buffer<usize>, Buffer<T>), Error=Error>>; // Result
+
+  /// Like `bcast_dot`, but stops with `Error::Cancelled` once `token` is
+  /// cancelled. The outputs come back with any error. The kernel checks
+  /// the token between chunks of the output, so a failed call may have
+  /// written part of `c`: its native copy goes out of date if another
+  /// device holds the old contents, otherwise they are undefined until
+  /// `c` is written again.
+  fn bcast_dot_with(&self,
+                    shape_a: BufferView<usize>,
+                    a: BufferView<T>,
+                    shape_b: BufferView<usize>,
+                    b: BufferView<T>,
+                    shape_c: Buffer<usize>,
+                    c: Buffer<T>,
+                    token: &CancelToken) ->
+    Box<Future<Item=(Buffer<usize>, Buffer<T>), Error=(Error, Buffer<usize>, Buffer<T>)>>;
+
+  /// Like `bcast_dot`, with the values of `a` conjugated.
+  fn bcast_dotc(&self,
+                shape_a: BufferView<usize>,
+                a: BufferView<T>,
+                shape_b: BufferView<usize>,
+                b: BufferView<T>,
+                shape_c: Buffer<usize>,
+                c: Buffer<T>) ->
+    Box<Future<Item=(Buffer<usize>, Buffer<T>), Error=Error>>;
+
+  /// Broadcasted dot product over the last dimension of two tensors. The
+  /// output shape is worked out on the host and `c` must hold exactly as
+  /// many elements.
+  fn tensor_dot<A: Into<TensorView<T>>, B: Into<TensorView<T>>>(&self,
+                                                                a: A,
+                                                                b: B,
+                                                                c: Buffer<T>) ->
+    Box<Future<Item=Tensor<T>, Error=Error>>;
+
+  /// Like `tensor_dot`, but stops with `Error::Cancelled` once `token` is
+  /// cancelled. `c` comes back with any error, written as with
+  /// `bcast_dot_with`.
+  fn tensor_dot_with<A: Into<TensorView<T>>, B: Into<TensorView<T>>>(&self,
+                                                                     a: A,
+                                                                     b: B,
+                                                                     c: Buffer<T>,
+                                                                     token: &CancelToken) ->
+    Box<Future<Item=Tensor<T>, Error=(Error, Buffer<T>)>>;
+
+  /// Like `tensor_dot`, with the values of `a` conjugated.
+  fn tensor_dotc<A: Into<TensorView<T>>, B: Into<TensorView<T>>>(&self,
+                                                                 a: A,
+                                                                 b: B,
+                                                                 c: Buffer<T>) ->
+    Box<Future<Item=Tensor<T>, Error=Error>>;
 }
@@ -1,13 +1,12 @@
 use std::collections::HashMap;
-use std::any::Any;
 use futures::Future;
-use futures::future::Shared;
-use popcorn::buffer::{Buffer, Error};
+use popcorn::buffer::{AnyBuffer, Buffer, Error};
+use popcorn::data_type::Element;
 use uuid::Uuid;
 
 pub struct Context {
-  inputs: HashMap<String, Box<Any>>,
-  cache: HashMap<Uuid, Box<Any>>
+  inputs: HashMap<String, AnyBuffer>,
+  cache: HashMap<Uuid, AnyBuffer>
 }
 
 pub trait Executable where Self: Sized {
@@ -17,9 +16,55 @@ pub trait Executable where Self: Sized {
   fn exec<'a>(&self, ctx: &'a mut Context) -> Box<Future<Item=Buffer<Self::Base>,Error=Error>>;
 }
 
+impl Default for Context {
+  fn default() -> Context { Context::new() }
+}
+
 impl Context {
-  fn get_input<Base: Send + Copy + 'static, E: Executable<Base=Base>>(&mut self,
-                                                                      name: &str) ->
-    Result<Shared<Box<Future<Item=Buffer<Base>,Error=Error>>> {
+  pub fn new() -> Context {
+    Context {
+      inputs: HashMap::new(),
+      cache: HashMap::new()
+    }
+  }
+
+  pub fn set_input<B: Into<AnyBuffer>>(&mut self, name: &str, buffer: B) {
+    self.inputs.insert(name.to_string(), buffer.into());
+  }
+
+  pub fn input(&self, name: &str) -> Option<&AnyBuffer> {
+    self.inputs.get(name)
+  }
+
+  /// Takes the input called `name` out of the context. Inputs holding
+  /// another element type than `T` are left in place.
+  pub fn take_input<T: Element>(&mut self, name: &str) -> Result<Option<Buffer<T>>, Error> {
+    let input = match self.inputs.remove(name) {
+      Some(input) => input,
+      None => return Ok(None)
+    };
+
+    match input.downcast() {
+      Ok(buffer) => Ok(Some(buffer)),
+      Err(input) => {
+        let err = Error::TypeMismatch {
+          expected: T::DATA_TYPE,
+          actual: input.dtype()
+        };
+        self.inputs.insert(name.to_string(), input);
+        Err(err)
+      }
+    }
+  }
+
+  pub fn cache<B: Into<AnyBuffer>>(&mut self, uid: Uuid, buffer: B) {
+    self.cache.insert(uid, buffer.into());
+  }
+
+  pub fn cached<T: Element>(&self, uid: &Uuid) -> Result<Option<&Buffer<T>>, Error> {
+    match self.cache.get(uid) {
+      Some(buffer) => buffer.downcast_ref().map(Some),
+      None => Ok(None)
     }
+  }
 }
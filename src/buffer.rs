@@ -1,23 +1,39 @@
 use futures::{Future, IntoFuture};
+use std::cmp;
 use std::marker::PhantomData;
 use std::mem;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::any::Any;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use cancel::{CancelToken, Cancelled};
+use data_type::{DataType, Element};
 use device::Device;
-use lock::{self, Lock, LockGuard};
-use std::ops::{Deref, DerefMut};
+use lock::{self, Lock, LockGuard, ReadGuard};
+use std::ops::{Deref, DerefMut, Range};
 
 use frameworks::native;
+#[cfg(feature = "sim")]
+use frameworks::sim;
 
 #[derive(Debug, Clone, Copy)]
 pub enum BufferSource {
   #[cfg(feature = "native")]
-  Native
+  Native,
+  #[cfg(feature = "sim")]
+  Sim
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum BufferDevice {
   #[cfg(feature = "native")]
-  Native(native::Device)
+  Native(native::Device),
+  #[cfg(feature = "sim")]
+  Sim(sim::Device)
 }
 
 #[cfg(feature = "native")]
@@ -30,10 +46,33 @@ impl<'a> From<&'a native::Device> for BufferDevice {
   fn from(dev: &'a native::Device) -> BufferDevice { BufferDevice::Native(dev.clone()) }
 }
 
+#[cfg(feature = "sim")]
+impl From<sim::Device> for BufferDevice {
+  fn from(dev: sim::Device) -> BufferDevice { BufferDevice::Sim(dev) }
+}
+
+#[cfg(feature = "sim")]
+impl<'a> From<&'a sim::Device> for BufferDevice {
+  fn from(dev: &'a sim::Device) -> BufferDevice { BufferDevice::Sim(dev.clone()) }
+}
+
+impl fmt::Display for BufferDevice {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      #[cfg(feature = "native")]
+      BufferDevice::Native(ref dev) => write!(f, "native device {}", dev.id()),
+      #[cfg(feature = "sim")]
+      BufferDevice::Sim(ref dev) => write!(f, "sim device {}", dev.id()),
+    }
+  }
+}
+
 #[derive(Debug)]
 pub enum BufferMemory {
   #[cfg(feature = "native")]
-  Native(native::Memory)
+  Native(native::Memory),
+  #[cfg(feature = "sim")]
+  Sim(sim::Memory)
 }
 
 #[derive(Debug, Clone)]
@@ -41,30 +80,167 @@ pub enum Error {
   #[cfg(feature = "native")]
   Native(native::Error),
 
+  #[cfg(feature = "sim")]
+  Sim(sim::Error),
+
   Lock(lock::Error),
+
+  Io(Arc<io::Error>),
+
+  // Buffer has no up-to-date copy to read from
   InvalidRawBuffer,
-  InvalidDevice,
-  InvalidBroadcast
+
+  // Buffer has no copy on the device
+  InvalidDevice(BufferDevice),
+
+  // Copy on the device is out of date and cannot be synced
+  StaleCopy(BufferDevice),
+
+  // Number of elements does not match the buffer
+  SizeMismatch { expected: usize, actual: usize },
+
+  // Element range reaches past the end of the buffer
+  OutOfRange { start: usize, end: usize, len: usize },
+
+  // Shapes cannot be broadcast together
+  ShapeMismatch { a: Vec<usize>, b: Vec<usize> },
+
+  // Shape does not hold the number of elements in the data
+  InvalidShape { shape: Vec<usize>, len: usize },
+
+  // Axis is past the last dimension
+  InvalidAxis { axis: usize, ndim: usize },
+
+  // Dynamically typed buffer holds another element type
+  TypeMismatch { expected: DataType, actual: DataType },
+
+  // Operation stopped because its cancel token was set
+  Cancelled
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      #[cfg(feature = "native")]
+      Error::Native(_) => write!(f, "native device error"),
+      #[cfg(feature = "sim")]
+      Error::Sim(_) => write!(f, "sim device error"),
+      Error::Lock(_) => write!(f, "buffer lock error"),
+      Error::Io(_) => write!(f, "buffer I/O error"),
+      Error::InvalidRawBuffer => write!(f, "buffer has no up-to-date copy"),
+      Error::InvalidDevice(ref dev) => write!(f, "buffer has no copy on {}", dev),
+      Error::StaleCopy(ref dev) => write!(f, "shared buffer is not up to date on {}", dev),
+      Error::SizeMismatch { expected, actual } =>
+        write!(f, "size mismatch: expected {} elements, got {}", expected, actual),
+      Error::OutOfRange { start, end, len } =>
+        write!(f, "out of range: elements {}..{} of buffer with {} elements", start, end, len),
+      Error::ShapeMismatch { ref a, ref b } =>
+        write!(f, "shape mismatch: cannot broadcast {:?} with {:?}", a, b),
+      Error::InvalidShape { ref shape, len } =>
+        write!(f, "invalid shape: {:?} does not hold {} elements", shape, len),
+      Error::InvalidAxis { axis, ndim } =>
+        write!(f, "invalid axis {} for {} dimensions", axis, ndim),
+      Error::TypeMismatch { expected, actual } =>
+        write!(f, "type mismatch: expected {} elements, got {}", expected, actual),
+      Error::Cancelled => write!(f, "buffer operation was cancelled")
+    }
+  }
+}
+
+impl error::Error for Error {
+  fn source(&self) -> Option<&(error::Error + 'static)> {
+    match *self {
+      #[cfg(feature = "native")]
+      Error::Native(ref err) => Some(err),
+      #[cfg(feature = "sim")]
+      Error::Sim(ref err) => Some(err),
+      Error::Lock(ref err) => Some(err),
+      Error::Io(ref err) => Some(&**err),
+      _ => None
+    }
+  }
 }
 
 #[cfg(feature = "native")]
 impl From<native::Error> for Error {
-  fn from(err: native::Error) -> Error { Error::Native(err) }
+  fn from(err: native::Error) -> Error {
+    match err {
+      native::Error::Cancelled => Error::Cancelled,
+      err => Error::Native(err)
+    }
+  }
+}
+
+#[cfg(feature = "sim")]
+impl From<sim::Error> for Error {
+  fn from(err: sim::Error) -> Error {
+    match err {
+      sim::Error::Cancelled => Error::Cancelled,
+      err => Error::Sim(err)
+    }
+  }
 }
 
 impl From<lock::Error> for Error {
   fn from(err: lock::Error) -> Error { Error::Lock(err) }
 }
 
+impl From<io::Error> for Error {
+  fn from(err: io::Error) -> Error { Error::Io(Arc::new(err)) }
+}
+
+impl From<Cancelled> for Error {
+  fn from(_: Cancelled) -> Error { Error::Cancelled }
+}
+
 pub struct Buffer<T: Copy + Sized + Send + 'static> {
   guard: LockGuard<RawBuffer<T>>
 }
 
+/// Shared, read-only handle to a buffer. Clones can be handed to as many
+/// in-flight operations as needed, writers wait until all are dropped.
+pub struct ReadBuffer<T: Copy + Sized + Send + 'static> {
+  guard: ReadGuard<RawBuffer<T>>
+}
+
+/// Read-only window of `len` elements starting at `offset` into a shared
+/// buffer. Views share the device copies and coherence state of their
+/// parent, which stays locked against writers while any view is alive.
+/// Writing through a window takes a `BufferViewMut`.
+pub struct BufferView<T: Copy + Sized + Send + 'static> {
+  parent: ReadBuffer<T>,
+  offset: usize,
+  len: usize
+}
+
+/// Writable window of `len` elements starting at `offset` into a
+/// buffer, which stays locked for as long as the view is alive. Writes
+/// through the view only mark its range modified, the rest of the
+/// parent keeps its coherence state.
+pub struct BufferViewMut<T: Copy + Sized + Send + 'static> {
+  parent: Buffer<T>,
+  offset: usize,
+  len: usize
+}
+
+/// Buffer whose element type is only known at runtime. It can be turned
+/// back into a `Buffer<T>` of the type it was created with.
+pub struct AnyBuffer {
+  dtype: DataType,
+  len: usize,
+  buffer: Box<Any + Send>
+}
+
 #[derive(Debug)]
 pub struct RawBuffer<T: Copy + Sized + Send + 'static> {
   size: usize,
   copies: HashMap<BufferDevice, BufferMemory>,
   latest_source: BufferSource,
+  // Devices whose copy holds the most up-to-date contents
+  latest_copies: HashSet<BufferDevice>,
+  // Copies that are only out of date in these element ranges, any
+  // other copy not in latest_copies is entirely out of date
+  stale_ranges: HashMap<BufferDevice, Vec<Range<usize>>>,
 
   _pd: PhantomData<T>,
 }
@@ -72,25 +248,146 @@ pub struct RawBuffer<T: Copy + Sized + Send + 'static> {
 impl<T: Send + Copy + Sized + 'static> RawBuffer<T> {
   pub fn new<D: Into<BufferDevice>>(dev: D, size: usize) -> Result<RawBuffer<T>, Error> {
     let bdev: BufferDevice = dev.into();
+    let copy = try!(Self::alloc_on_device(&bdev, size * mem::size_of::<T>()));
+    Ok(Self::with_copy(bdev, copy, size))
+  }
+
+  /// Creates a buffer whose only copy is `vec` itself. Devices that
+  /// share host memory take over the allocation without copying.
+  pub fn from_vec<D: Into<BufferDevice>>(dev: D, vec: Vec<T>) -> Result<RawBuffer<T>, Error> {
+    let bdev: BufferDevice = dev.into();
+    let size = vec.len();
+    let copy = try!(Self::memory_from_vec_on_device(&bdev, vec));
+    Ok(Self::with_copy(bdev, copy, size))
+  }
+
+  /// Creates a buffer whose only copy is `mem`, which has to hold a whole
+  /// number of properly aligned elements.
+  #[cfg(feature = "native")]
+  pub fn from_native_memory(dev: &native::Device, mem: native::Memory) -> Result<RawBuffer<T>, Error> {
+    let size = try!(mem.try_as_slice::<T>()).len();
+    Ok(Self::with_copy(BufferDevice::Native(dev.clone()), BufferMemory::Native(mem), size))
+  }
+
+  fn with_copy(bdev: BufferDevice, copy: BufferMemory, size: usize) -> RawBuffer<T> {
     let mut copies = HashMap::new();
+    let mut latest_copies = HashSet::new();
     let latest_source = Self::device_source(&bdev);
-    let copy = try!(Self::alloc_on_device(&bdev, size * mem::size_of::<T>()));
-    copies.insert(bdev, copy);
+    copies.insert(bdev.clone(), copy);
+    latest_copies.insert(bdev);
 
-    Ok(RawBuffer {
+    RawBuffer {
       size: size,
       copies: copies,
       latest_source: latest_source,
+      latest_copies: latest_copies,
+      stale_ranges: HashMap::new(),
       _pd: PhantomData
-    })
+    }
   }
 
+  pub fn len(&self) -> usize { self.size }
+
+  pub fn is_empty(&self) -> bool { self.size == 0 }
+
   pub fn source(&self) -> BufferSource { self.latest_source }
 
+  pub fn has_copy(&self, dev: &BufferDevice) -> bool {
+    self.copies.contains_key(dev)
+  }
+
+  pub fn is_latest(&self, dev: &BufferDevice) -> bool {
+    self.latest_copies.contains(dev)
+  }
+
+  pub fn latest_device(&self) -> Option<&BufferDevice> {
+    self.latest_copies.iter().next()
+  }
+
+  /// Whether the elements in `range` are up to date on `dev`.
+  pub fn is_range_latest(&self, dev: &BufferDevice, range: &Range<usize>) -> bool {
+    if self.is_latest(dev) {
+      return true
+    }
+
+    match self.stale_ranges.get(dev) {
+      Some(stale) => stale.iter().all(|r| r.end <= range.start || r.start >= range.end),
+      None => false
+    }
+  }
+
+  /// Marks the copy on `dev` as the only up-to-date copy. Every other
+  /// copy becomes stale and will be refreshed on its next sync.
+  pub fn mark_modified(&mut self, dev: &BufferDevice) {
+    self.latest_copies.clear();
+    self.latest_copies.insert(dev.clone());
+    self.stale_ranges.clear();
+    self.latest_source = Self::device_source(dev);
+  }
+
+  /// Marks `range` as written on `dev`, which has to be up to date. Other
+  /// up-to-date copies only go stale in that range.
+  pub fn mark_range_modified(&mut self, dev: &BufferDevice, range: Range<usize>) {
+    if range.start >= range.end {
+      return
+    }
+
+    for other in self.latest_copies.drain() {
+      if other != *dev {
+        self.stale_ranges.insert(other, Vec::new());
+      }
+    }
+    for stale in self.stale_ranges.values_mut() {
+      add_range(stale, range.clone());
+    }
+
+    self.latest_copies.insert(dev.clone());
+    self.latest_source = Self::device_source(dev);
+  }
+
+  /// Marks the copy on `dev` as out of date after a write to it failed
+  /// part way. The copy stays up to date if no other copy is, there is
+  /// nothing left to refresh it from.
+  pub fn mark_stale(&mut self, dev: &BufferDevice) {
+    if !self.latest_copies.iter().any(|other| other != dev) {
+      return
+    }
+
+    self.latest_copies.remove(dev);
+    self.stale_ranges.remove(dev);
+    self.latest_source = Self::device_source(self.latest_copies.iter().next().unwrap());
+  }
+
+  fn mark_synced(&mut self, dev: BufferDevice) {
+    self.stale_ranges.remove(&dev);
+    self.latest_copies.insert(dev);
+  }
+
+  fn check_range(&self, range: &Range<usize>) -> Result<(), Error> {
+    if range.start > range.end || range.end > self.size {
+      return Err(Error::OutOfRange {
+        start: range.start,
+        end: range.end,
+        len: self.size
+      })
+    }
+
+    Ok(())
+  }
+
+  fn take_or_alloc(&mut self, dev: &BufferDevice) -> Result<BufferMemory, Error> {
+    match self.copies.remove(dev) {
+      Some(mem) => Ok(mem),
+      None => Self::alloc_on_device(dev, self.size * mem::size_of::<T>())
+    }
+  }
+
   pub fn device_source(dev: &BufferDevice) -> BufferSource {
     match *dev {
       #[cfg(feature = "native")]
       BufferDevice::Native(_) => BufferSource::Native,
+      #[cfg(feature = "sim")]
+      BufferDevice::Sim(_) => BufferSource::Sim,
     }
   }
 
@@ -98,6 +395,23 @@ impl<T: Send + Copy + Sized + 'static> RawBuffer<T> {
     match *dev {
       #[cfg(feature = "native")]
       BufferDevice::Native(ref dev_n) => Self::alloc_on_device_native(dev_n, size),
+      #[cfg(feature = "sim")]
+      BufferDevice::Sim(ref dev_s) => dev_s.alloc_memory(size).
+        map(BufferMemory::Sim).
+        map_err(Error::Sim),
+    }
+  }
+
+  fn memory_from_vec_on_device(dev: &BufferDevice, vec: Vec<T>) -> Result<BufferMemory, Error> {
+    match *dev {
+      #[cfg(feature = "native")]
+      BufferDevice::Native(ref dev_n) => dev_n.memory_from_vec(vec).
+        map(BufferMemory::Native).
+        map_err(Error::Native),
+      #[cfg(feature = "sim")]
+      BufferDevice::Sim(ref dev_s) => dev_s.memory_from_vec(vec).
+        map(BufferMemory::Sim).
+        map_err(Error::Sim),
     }
   }
 
@@ -109,23 +423,35 @@ impl<T: Send + Copy + Sized + 'static> RawBuffer<T> {
 
   #[cfg(feature = "native")]
   pub fn native_memory(&self, dev: &native::Device) -> Result<&native::Memory, Error> {
-    match self.copies.get(&BufferDevice::Native(dev.clone())) {
-      Some(mem) => {
-        let BufferMemory::Native(ref nm) = *mem;
-        Ok(nm)
-      },
-      None => Err(Error::InvalidDevice)
+    let bdev = BufferDevice::Native(dev.clone());
+    match self.copies.get(&bdev) {
+      Some(&BufferMemory::Native(ref nm)) => Ok(nm),
+      _ => Err(Error::InvalidDevice(bdev))
     }
   }
 
+  /// Mutable access to the native copy. The copy is assumed to be
+  /// written to, so it becomes the only up-to-date copy of the buffer.
   #[cfg(feature = "native")]
   pub fn native_memory_mut(&mut self, dev: &native::Device) -> Result<&mut native::Memory, Error> {
-    match self.copies.get_mut(&BufferDevice::Native(dev.clone())) {
-      Some(mem) => {
-        let BufferMemory::Native(ref mut nm) = *mem;
-        Ok(nm)
-      },
-      None => Err(Error::InvalidDevice)
+    let bdev = BufferDevice::Native(dev.clone());
+    if !self.copies.contains_key(&bdev) {
+      return Err(Error::InvalidDevice(bdev))
+    }
+
+    self.mark_modified(&bdev);
+    self.native_memory_mut_unmarked(dev)
+  }
+
+  /// Mutable access to the native copy that leaves the coherence state
+  /// alone. Writers call `mark_modified` once the write went through, or
+  /// `mark_stale` if it failed.
+  #[cfg(feature = "native")]
+  pub fn native_memory_mut_unmarked(&mut self, dev: &native::Device) -> Result<&mut native::Memory, Error> {
+    let bdev = BufferDevice::Native(dev.clone());
+    match self.copies.get_mut(&bdev) {
+      Some(&mut BufferMemory::Native(ref mut nm)) => Ok(nm),
+      _ => Err(Error::InvalidDevice(bdev))
     }
   }
 }
@@ -153,69 +479,659 @@ impl<T: Send + Copy + Sized + 'static> Buffer<T> {
     Ok(raw.into())
   }
 
+  pub fn from_vec<D: Into<BufferDevice>>(dev: D, vec: Vec<T>) -> Result<Buffer<T>, Error> {
+    let raw = try!(RawBuffer::from_vec(dev, vec));
+    Ok(raw.into())
+  }
+
+  /// Creates a buffer backed by a memory mapping of the whole file at
+  /// `path`. Pages are loaded lazily and read-only mappings share the
+  /// page cache with every other process mapping the file.
+  #[cfg(feature = "native")]
+  pub fn map_file<P: AsRef<Path>>(dev: &native::Device,
+                                  path: P,
+                                  mode: native::MapMode) -> Result<Buffer<T>, Error> {
+    let file = try!(File::open(path));
+    let len = try!(file.metadata()).len() as usize;
+    let mem = try!(native::Memory::map_file(&file, 0, len, mode));
+    let raw = try!(RawBuffer::from_native_memory(dev, mem));
+    Ok(raw.into())
+  }
+
   pub fn from_lock(lock: Lock<RawBuffer<T>>) -> Result<Buffer<T>, Error> {
     let guard = try!(lock.try_lock());
     Ok(Self::from_guard(guard))
   }
 
+  /// Resolves once every other holder of the buffer has released it.
+  pub fn lock(lock: &Lock<RawBuffer<T>>) -> Box<Future<Item=Buffer<T>,Error=Error>> {
+    Box::new(lock.lock().map(Self::from_guard).map_err(Error::Lock))
+  }
+
   pub fn from_guard(guard: LockGuard<RawBuffer<T>>) -> Buffer<T> {
     Buffer {
       guard: guard
     }
   }
 
+  pub fn handle(&self) -> Lock<RawBuffer<T>> {
+    self.guard.handle()
+  }
+
+  /// Gives up exclusive access so the buffer can be read by several
+  /// operations at once. Copies that should be read later need to be
+  /// synced before sharing.
+  pub fn share(self) -> ReadBuffer<T> {
+    ReadBuffer::from_guard(self.guard.downgrade())
+  }
+
+  pub fn view(self, offset: usize, len: usize) -> Result<BufferView<T>, Error> {
+    BufferView::new(self.share(), offset, len)
+  }
+
+  pub fn view_mut(self, offset: usize, len: usize) -> Result<BufferViewMut<T>, Error> {
+    BufferViewMut::new(self, offset, len)
+  }
+
   pub fn sync_from_vec<D: Into<BufferDevice>>(mut self, vec: Vec<T>, dev: D) -> Box<Future<Item=Buffer<T>,Error=Error>> {
     let bdev: BufferDevice = dev.into();
-    let copy = self.copies.remove(&bdev);
-    match copy {
-      Some(mem) => {
-        match bdev {
-          #[cfg(feature = "native")]
-          BufferDevice::Native(ref dev) => {
-            let BufferMemory::Native(m) = mem;
-            let new_dev = BufferDevice::Native(dev.clone());
-            Box::new(dev.sync_from_vec(m, vec).map(move |mem| {
-              self.latest_source = RawBuffer::<T>::device_source(&new_dev);
-              self.copies.insert(new_dev, BufferMemory::Native(mem));
-              self
-            }).map_err(Error::Native))
-          },
-        }
-      },
-      None => Box::new(Err(Error::InvalidDevice).into_future())
+    if vec.len() != self.len() {
+      return Box::new(Err(Error::SizeMismatch {
+        expected: self.len(),
+        actual: vec.len()
+      }).into_future())
     }
+
+    let copy = match self.take_or_alloc(&bdev) {
+      Ok(copy) => copy,
+      Err(err) => return Box::new(Err(err).into_future())
+    };
+
+    let synced: Box<Future<Item=BufferMemory,Error=Error>> = match (&bdev, copy) {
+      #[cfg(feature = "native")]
+      (&BufferDevice::Native(ref dev), BufferMemory::Native(m)) =>
+        Box::new(dev.sync_from_vec(m, vec).map(BufferMemory::Native).map_err(Error::Native)),
+      #[cfg(feature = "sim")]
+      (&BufferDevice::Sim(ref dev), BufferMemory::Sim(m)) =>
+        Box::new(dev.sync_from_vec(m, vec).map(BufferMemory::Sim).map_err(Error::Sim)),
+      #[cfg(feature = "sim")]
+      _ => Box::new(Err(Error::InvalidDevice(bdev.clone())).into_future())
+    };
+
+    Box::new(synced.map(move |mem| {
+      self.copies.insert(bdev.clone(), mem);
+      self.mark_modified(&bdev);
+      self
+    }))
   }
 
-  pub fn sync_to_vec<D: Into<BufferDevice>>(mut self, dev: D) -> Box<Future<Item=(Buffer<T>, Vec<T>),Error=Error>> {
+  pub fn sync_to_vec<D: Into<BufferDevice>>(self, dev: D) -> Box<Future<Item=(Buffer<T>, Vec<T>),Error=Error>> {
+    Box::new(self.sync_to_vec_with(dev, &CancelToken::new()).map_err(|(err, _)| err))
+  }
+
+  /// Like `sync_to_vec`, but stops with `Error::Cancelled` during the
+  /// sync or before the read once `token` is cancelled. The buffer comes
+  /// back along with any error.
+  pub fn sync_to_vec_with<D: Into<BufferDevice>>(self,
+                                                 dev: D,
+                                                 token: &CancelToken) -> Box<Future<Item=(Buffer<T>, Vec<T>),Error=(Error, Buffer<T>)>> {
     let bdev: BufferDevice = dev.into();
+    let token = token.clone();
+    Box::new(self.sync_with(&bdev, &token).and_then(move |buf| {
+      let read: Box<Future<Item=(Buffer<T>, Vec<T>),Error=(Error, Buffer<T>)>> = match token.check() {
+        Ok(()) => buf.copy_to_vec(bdev),
+        Err(err) => Box::new(Err((err.into(), buf)).into_future())
+      };
+      read
+    }))
+  }
+
+  /// Writes `data` at element `offset` of an up-to-date copy. Other
+  /// copies only go stale in the written range.
+  pub fn write_range(mut self, offset: usize, data: &[T]) -> Box<Future<Item=Buffer<T>,Error=Error>> {
+    let range = match element_range(offset, data.len(), self.size) {
+      Ok(range) => range,
+      Err(err) => return Box::new(Err(err).into_future())
+    };
+
+    let bdev = match self.latest_device() {
+      Some(bdev) => bdev.clone(),
+      None => return Box::new(Err(Error::InvalidRawBuffer).into_future())
+    };
+    let copy = match self.copies.remove(&bdev) {
+      Some(copy) => copy,
+      None => return Box::new(Err(Error::InvalidDevice(bdev)).into_future())
+    };
+
+    let written: Box<Future<Item=BufferMemory,Error=Error>> = match (&bdev, copy) {
+      #[cfg(feature = "native")]
+      (&BufferDevice::Native(ref dev), BufferMemory::Native(m)) =>
+        Box::new(dev.write_range(m, offset, data.to_vec()).map(BufferMemory::Native).map_err(Error::Native)),
+      #[cfg(feature = "sim")]
+      (&BufferDevice::Sim(ref dev), BufferMemory::Sim(m)) =>
+        Box::new(dev.write_range(m, offset, data.to_vec()).map(BufferMemory::Sim).map_err(Error::Sim)),
+      #[cfg(feature = "sim")]
+      _ => Box::new(Err(Error::InvalidDevice(bdev.clone())).into_future())
+    };
+
+    Box::new(written.map(move |mem| {
+      self.copies.insert(bdev.clone(), mem);
+      self.mark_range_modified(&bdev, range);
+      self
+    }))
+  }
+
+  /// Reads the elements in `range` from an up-to-date copy without
+  /// syncing the rest of the buffer.
+  pub fn read_range(mut self, range: Range<usize>) -> Box<Future<Item=(Buffer<T>, Vec<T>),Error=Error>> {
+    if let Err(err) = self.check_range(&range) {
+      return Box::new(Err(err).into_future())
+    }
+
+    let bdev = match self.latest_device() {
+      Some(bdev) => bdev.clone(),
+      None => return Box::new(Err(Error::InvalidRawBuffer).into_future())
+    };
+    let copy = match self.copies.remove(&bdev) {
+      Some(copy) => copy,
+      None => return Box::new(Err(Error::InvalidDevice(bdev)).into_future())
+    };
+
+    let read: Box<Future<Item=(BufferMemory, Vec<T>),Error=Error>> = match (&bdev, copy) {
+      #[cfg(feature = "native")]
+      (&BufferDevice::Native(ref dev), BufferMemory::Native(m)) =>
+        Box::new(dev.read_range(m, range).map(|(mem, vec)| (BufferMemory::Native(mem), vec)).map_err(Error::Native)),
+      #[cfg(feature = "sim")]
+      (&BufferDevice::Sim(ref dev), BufferMemory::Sim(m)) =>
+        Box::new(dev.read_range(m, range).map(|(mem, vec)| (BufferMemory::Sim(mem), vec)).map_err(Error::Sim)),
+      #[cfg(feature = "sim")]
+      _ => Box::new(Err(Error::InvalidDevice(bdev.clone())).into_future())
+    };
+
+    Box::new(read.map(move |(mem, vec)| {
+      self.copies.insert(bdev, mem);
+      (self, vec)
+    }))
+  }
+
+  /// Consumes the buffer and returns its contents from the copy on `dev`.
+  /// Devices that share host memory hand over the allocation without
+  /// copying when its layout allows it.
+  pub fn into_vec<D: Into<BufferDevice>>(self, dev: D) -> Box<Future<Item=Vec<T>,Error=Error>> {
+    let bdev: BufferDevice = dev.into();
+    Box::new(self.sync(&bdev).and_then(move |mut buf| {
+      let copy = buf.copies.remove(&bdev);
+      let res: Box<Future<Item=Vec<T>,Error=Error>> = match (&bdev, copy) {
+        #[cfg(feature = "native")]
+        (&BufferDevice::Native(ref dev), Some(BufferMemory::Native(m))) =>
+          Box::new(dev.into_vec(m).map_err(Error::Native)),
+        #[cfg(feature = "sim")]
+        (&BufferDevice::Sim(ref dev), Some(BufferMemory::Sim(m))) =>
+          Box::new(dev.into_vec(m).map_err(Error::Sim)),
+        _ => Box::new(Err(Error::InvalidDevice(bdev.clone())).into_future())
+      };
+      res
+    }))
+  }
+
+  // A failed read loses the copy it was reading, the buffer comes back
+  //   without it
+  fn copy_to_vec(mut self, bdev: BufferDevice) -> Box<Future<Item=(Buffer<T>, Vec<T>),Error=(Error, Buffer<T>)>> {
     let copy = self.copies.remove(&bdev);
-    match copy {
-      Some(mem) => {
-        match bdev {
-          #[cfg(feature = "native")]
-          BufferDevice::Native(ref dev) => {
-            let BufferMemory::Native(m) = mem;
-            let new_dev = BufferDevice::Native(dev.clone());
-            Box::new(dev.sync_to_vec(m).map(move |(mem, vec)| {
-              self.copies.insert(new_dev, BufferMemory::Native(mem));
-              (self, vec)
-            }).map_err(Error::Native))
-          },
+    let read: Box<Future<Item=(BufferMemory, Vec<T>),Error=Error>> = match (&bdev, copy) {
+      #[cfg(feature = "native")]
+      (&BufferDevice::Native(ref dev), Some(BufferMemory::Native(m))) =>
+        Box::new(dev.sync_to_vec(m).map(|(mem, vec)| (BufferMemory::Native(mem), vec)).map_err(Error::Native)),
+      #[cfg(feature = "sim")]
+      (&BufferDevice::Sim(ref dev), Some(BufferMemory::Sim(m))) =>
+        Box::new(dev.sync_to_vec(m).map(|(mem, vec)| (BufferMemory::Sim(mem), vec)).map_err(Error::Sim)),
+      (_, copy) => {
+        if let Some(copy) = copy {
+          self.copies.insert(bdev.clone(), copy);
         }
+        return Box::new(Err((Error::InvalidDevice(bdev), self)).into_future())
+      }
+    };
+
+    Box::new(read.then(move |res| match res {
+      Ok((mem, vec)) => {
+        self.copies.insert(bdev, mem);
+        Ok((self, vec))
       },
-      None => Box::new(Err(Error::InvalidDevice).into_future())
-    }
+      Err(err) => {
+        self.latest_copies.remove(&bdev);
+        self.stale_ranges.remove(&bdev);
+        Err((err, self))
+      }
+    }))
   }
 
+  /// Makes the copy on `dev` up to date, allocating it first if the
+  /// buffer has no copy on that device yet. A failed sync releases the
+  /// buffer with its copies as they were, `sync_with` hands it back.
   pub fn sync(self, dev: &BufferDevice) -> Box<Future<Item=Buffer<T>,Error=Error>> {
-    match self.source() {
+    Box::new(self.sync_with(dev, &CancelToken::new()).map_err(|(err, _)| err))
+  }
+
+  /// Like `sync`, but fails with `Error::Cancelled` if `token` is
+  /// cancelled before the copy starts or while it runs. The buffer comes
+  /// back along with any error, every copy as it was and the one on
+  /// `dev` out of date.
+  pub fn sync_with(mut self, dev: &BufferDevice, token: &CancelToken) -> Box<Future<Item=Buffer<T>,Error=(Error, Buffer<T>)>> {
+    if let Err(err) = token.check() {
+      return Box::new(Err((err.into(), self)).into_future())
+    }
+
+    if self.is_latest(dev) {
+      return Box::new(Ok(self).into_future())
+    }
+
+    // Copies that went stale through ranged writes only need those
+    // ranges refreshed
+    let size = mem::size_of::<T>();
+    let ranges: Option<Vec<Range<usize>>> = self.stale_ranges.get(dev).map(|stale| {
+      stale.iter().map(|r| (r.start * size)..(r.end * size)).collect()
+    });
+
+    let src_dev = match self.latest_device() {
+      Some(src_dev) if self.copies.contains_key(src_dev) => src_dev.clone(),
+      _ => return Box::new(Err((Error::InvalidRawBuffer, self)).into_future())
+    };
+    let dst = match self.take_or_alloc(dev) {
+      Ok(dst) => dst,
+      Err(err) => return Box::new(Err((err, self)).into_future())
+    };
+    let src = self.copies.remove(&src_dev).unwrap();
+
+    let copied: Box<Future<Item=(BufferMemory, BufferMemory),Error=(Error, BufferMemory, BufferMemory)>> = match (&src_dev, src, dev, dst) {
       #[cfg(feature = "native")]
-      BufferSource::Native => {
-        match *dev {
-          #[cfg(feature = "native")]
-          BufferDevice::Native(_) => Box::new(Ok(self).into_future()),
-        }
+      (_, BufferMemory::Native(src), &BufferDevice::Native(ref dst_ndev), BufferMemory::Native(dst)) => {
+        let copied = match ranges {
+          Some(ranges) => dst_ndev.sync_ranges_from_memory_with(dst, src, ranges, token),
+          None => dst_ndev.sync_from_memory_with(dst, src, token)
+        };
+        Box::new(copied.
+                 map(|(dst, src)| (BufferMemory::Native(dst), BufferMemory::Native(src))).
+                 map_err(|(err, dst, src)| (Error::from(err), BufferMemory::Native(dst), BufferMemory::Native(src))))
       },
+
+      // Transfers to and from a simulated device run on its threads
+      #[cfg(feature = "sim")]
+      (_, BufferMemory::Native(src), &BufferDevice::Sim(ref dst_sdev), BufferMemory::Sim(dst)) =>
+        Box::new(dst_sdev.upload(dst, src, ranges, token).
+                 map(|(dst, src)| (BufferMemory::Sim(dst), BufferMemory::Native(src))).
+                 map_err(|(err, dst, src)| (Error::from(err), BufferMemory::Sim(dst), BufferMemory::Native(src)))),
+      #[cfg(feature = "sim")]
+      (&BufferDevice::Sim(ref src_sdev), BufferMemory::Sim(src), &BufferDevice::Native(ref dst_ndev), BufferMemory::Native(dst)) =>
+        Box::new(src_sdev.download(dst_ndev, dst, src, ranges, token).
+                 map(|(dst, src)| (BufferMemory::Native(dst), BufferMemory::Sim(src))).
+                 map_err(|(err, dst, src)| (Error::from(err), BufferMemory::Native(dst), BufferMemory::Sim(src)))),
+      #[cfg(feature = "sim")]
+      (_, BufferMemory::Sim(src), &BufferDevice::Sim(ref dst_sdev), BufferMemory::Sim(dst)) =>
+        Box::new(dst_sdev.copy_from_device(dst, src, ranges, token).
+                 map(|(dst, src)| (BufferMemory::Sim(dst), BufferMemory::Sim(src))).
+                 map_err(|(err, dst, src)| (Error::from(err), BufferMemory::Sim(dst), BufferMemory::Sim(src)))),
+      #[cfg(feature = "sim")]
+      (_, src, _, dst) => Box::new(Err((Error::InvalidDevice(dev.clone()), dst, src)).into_future())
+    };
+
+    // Both memories go back whatever the outcome, the copy on `dev` only
+    // counts as up to date if it went through
+    let dst_dev = dev.clone();
+    let token = token.clone();
+    Box::new(copied.then(move |res| {
+      let (res, dst, src) = match res {
+        Ok((dst, src)) => (token.check().map_err(Error::from), dst, src),
+        Err((err, dst, src)) => (Err(err), dst, src)
+      };
+      self.copies.insert(src_dev, src);
+      self.copies.insert(dst_dev.clone(), dst);
+
+      match res {
+        Ok(()) => {
+          self.mark_synced(dst_dev);
+          Ok(self)
+        },
+        Err(err) => Err((err, self))
+      }
+    }))
+  }
+}
+
+impl<T: Send + Copy + Sized + 'static> Deref for ReadBuffer<T> {
+  type Target = RawBuffer<T>;
+
+  fn deref(&self) -> &RawBuffer<T> { &self.guard }
+}
+
+impl<T: Send + Copy + Sized + 'static> Clone for ReadBuffer<T> {
+  fn clone(&self) -> ReadBuffer<T> {
+    ReadBuffer::from_guard(self.guard.clone())
+  }
+}
+
+impl<T: Send + Copy + Sized + 'static> From<Buffer<T>> for ReadBuffer<T> {
+  fn from(buf: Buffer<T>) -> ReadBuffer<T> { buf.share() }
+}
+
+impl<T: Send + Copy + Sized + 'static> ReadBuffer<T> {
+  pub fn from_lock(lock: Lock<RawBuffer<T>>) -> Result<ReadBuffer<T>, Error> {
+    let guard = try!(lock.try_read());
+    Ok(Self::from_guard(guard))
+  }
+
+  /// Resolves once no writer holds the buffer anymore.
+  pub fn read(lock: &Lock<RawBuffer<T>>) -> Box<Future<Item=ReadBuffer<T>,Error=Error>> {
+    Box::new(lock.read().map(Self::from_guard).map_err(Error::Lock))
+  }
+
+  pub fn from_guard(guard: ReadGuard<RawBuffer<T>>) -> ReadBuffer<T> {
+    ReadBuffer {
+      guard: guard
+    }
+  }
+
+  pub fn handle(&self) -> Lock<RawBuffer<T>> {
+    self.guard.handle()
+  }
+
+  pub fn view(&self, offset: usize, len: usize) -> Result<BufferView<T>, Error> {
+    BufferView::new(self.clone(), offset, len)
+  }
+
+  /// Takes back exclusive access if this is the last reader.
+  pub fn try_unshare(self) -> Result<Buffer<T>, ReadBuffer<T>> {
+    self.guard.try_upgrade().
+      map(Buffer::from_guard).
+      map_err(ReadBuffer::from_guard)
+  }
+
+  /// Shared buffers cannot move data around, so this only checks that
+  /// the copy on `dev` is up to date.
+  pub fn sync(self, dev: &BufferDevice) -> Box<Future<Item=ReadBuffer<T>,Error=Error>> {
+    if self.is_latest(dev) {
+      Box::new(Ok(self).into_future())
+    } else {
+      Box::new(Err(Error::StaleCopy(dev.clone())).into_future())
+    }
+  }
+}
+
+impl<T: Send + Copy + Sized + 'static> Clone for BufferView<T> {
+  fn clone(&self) -> BufferView<T> {
+    BufferView {
+      parent: self.parent.clone(),
+      offset: self.offset,
+      len: self.len
+    }
+  }
+}
+
+impl<T: Send + Copy + Sized + 'static> From<ReadBuffer<T>> for BufferView<T> {
+  fn from(buf: ReadBuffer<T>) -> BufferView<T> {
+    let len = buf.len();
+
+    BufferView {
+      parent: buf,
+      offset: 0,
+      len: len
     }
   }
 }
+
+impl<T: Send + Copy + Sized + 'static> From<Buffer<T>> for BufferView<T> {
+  fn from(buf: Buffer<T>) -> BufferView<T> { buf.share().into() }
+}
+
+impl<T: Send + Copy + Sized + 'static> BufferView<T> {
+  pub fn new(parent: ReadBuffer<T>, offset: usize, len: usize) -> Result<BufferView<T>, Error> {
+    try!(element_range(offset, len, parent.len()));
+
+    Ok(BufferView {
+      parent: parent,
+      offset: offset,
+      len: len
+    })
+  }
+
+  pub fn parent(&self) -> &ReadBuffer<T> { &self.parent }
+
+  pub fn offset(&self) -> usize { self.offset }
+
+  pub fn len(&self) -> usize { self.len }
+
+  pub fn is_empty(&self) -> bool { self.len == 0 }
+
+  /// Range of the parent covered by this view.
+  pub fn range(&self) -> Range<usize> { self.offset..(self.offset + self.len) }
+
+  /// Narrows the view further, `offset` is relative to this view.
+  pub fn view(&self, offset: usize, len: usize) -> Result<BufferView<T>, Error> {
+    try!(element_range(offset, len, self.len));
+    BufferView::new(self.parent.clone(), self.offset + offset, len)
+  }
+
+  pub fn is_latest(&self, dev: &BufferDevice) -> bool {
+    self.parent.is_range_latest(dev, &self.range())
+  }
+
+  /// Checks that the viewed range is up to date on `dev`. The rest of
+  /// the parent may still be stale there.
+  pub fn sync(self, dev: &BufferDevice) -> Box<Future<Item=BufferView<T>,Error=Error>> {
+    if self.is_latest(dev) {
+      Box::new(Ok(self).into_future())
+    } else {
+      Box::new(Err(Error::StaleCopy(dev.clone())).into_future())
+    }
+  }
+
+  #[cfg(feature = "native")]
+  pub fn native_slice(&self, dev: &native::Device) -> Result<&[T], Error> {
+    let slice: &[T] = try!(try!(self.parent.native_memory(dev)).try_as_slice());
+    Ok(&slice[self.range()])
+  }
+}
+
+impl<T: Send + Copy + Sized + 'static> BufferViewMut<T> {
+  pub fn new(parent: Buffer<T>, offset: usize, len: usize) -> Result<BufferViewMut<T>, Error> {
+    try!(element_range(offset, len, parent.len()));
+
+    Ok(BufferViewMut {
+      parent: parent,
+      offset: offset,
+      len: len
+    })
+  }
+
+  pub fn parent(&self) -> &Buffer<T> { &self.parent }
+
+  /// Ends the view, handing back the parent buffer.
+  pub fn into_parent(self) -> Buffer<T> { self.parent }
+
+  pub fn offset(&self) -> usize { self.offset }
+
+  pub fn len(&self) -> usize { self.len }
+
+  pub fn is_empty(&self) -> bool { self.len == 0 }
+
+  /// Range of the parent covered by this view.
+  pub fn range(&self) -> Range<usize> { self.offset..(self.offset + self.len) }
+
+  /// Narrows the view further, `offset` is relative to this view.
+  pub fn view_mut(self, offset: usize, len: usize) -> Result<BufferViewMut<T>, Error> {
+    try!(element_range(offset, len, self.len));
+    BufferViewMut::new(self.parent, self.offset + offset, len)
+  }
+
+  pub fn is_latest(&self, dev: &BufferDevice) -> bool {
+    self.parent.is_range_latest(dev, &self.range())
+  }
+
+  /// Writes `data` at element `offset` of the view, like
+  /// `Buffer::write_range` does on the parent.
+  pub fn write_range(self, offset: usize, data: &[T]) -> Box<Future<Item=BufferViewMut<T>,Error=Error>> {
+    if let Err(err) = element_range(offset, data.len(), self.len) {
+      return Box::new(Err(err).into_future())
+    }
+
+    let (start, len) = (self.offset, self.len);
+    Box::new(self.parent.write_range(start + offset, data).map(move |parent| {
+      BufferViewMut {
+        parent: parent,
+        offset: start,
+        len: len
+      }
+    }))
+  }
+
+  /// Reads `range`, relative to the view, from an up-to-date copy.
+  pub fn read_range(self, range: Range<usize>) -> Box<Future<Item=(BufferViewMut<T>, Vec<T>),Error=Error>> {
+    if range.start > range.end || range.end > self.len {
+      return Box::new(Err(Error::OutOfRange {
+        start: range.start,
+        end: range.end,
+        len: self.len
+      }).into_future())
+    }
+
+    let (start, len) = (self.offset, self.len);
+    let range = (start + range.start)..(start + range.end);
+    Box::new(self.parent.read_range(range).map(move |(parent, vec)| {
+      let view = BufferViewMut {
+        parent: parent,
+        offset: start,
+        len: len
+      };
+      (view, vec)
+    }))
+  }
+
+  #[cfg(feature = "native")]
+  pub fn native_slice(&self, dev: &native::Device) -> Result<&[T], Error> {
+    let slice: &[T] = try!(try!(self.parent.native_memory(dev)).try_as_slice());
+    Ok(&slice[self.range()])
+  }
+
+  /// Mutable access to the viewed range of the native copy, which has to
+  /// be up to date as a whole. Only that range goes stale elsewhere.
+  #[cfg(feature = "native")]
+  pub fn native_slice_mut(&mut self, dev: &native::Device) -> Result<&mut [T], Error> {
+    let bdev = BufferDevice::Native(dev.clone());
+    if !self.parent.copies.contains_key(&bdev) {
+      return Err(Error::InvalidDevice(bdev))
+    }
+    if !self.parent.is_latest(&bdev) {
+      return Err(Error::StaleCopy(bdev))
+    }
+
+    let range = self.range();
+    self.parent.mark_range_modified(&bdev, range.clone());
+    let slice: &mut [T] = match self.parent.copies.get_mut(&bdev) {
+      Some(&mut BufferMemory::Native(ref mut nm)) => try!(nm.try_as_mut_slice()),
+      _ => return Err(Error::InvalidDevice(bdev))
+    };
+    Ok(&mut slice[range])
+  }
+}
+
+impl<T: Send + Copy + Sized + 'static> From<Buffer<T>> for BufferViewMut<T> {
+  fn from(buf: Buffer<T>) -> BufferViewMut<T> {
+    let len = buf.len();
+    BufferViewMut {
+      parent: buf,
+      offset: 0,
+      len: len
+    }
+  }
+}
+
+impl AnyBuffer {
+  pub fn new<T: Element>(buffer: Buffer<T>) -> AnyBuffer {
+    AnyBuffer {
+      dtype: T::DATA_TYPE,
+      len: buffer.len(),
+      buffer: Box::new(buffer)
+    }
+  }
+
+  pub fn dtype(&self) -> DataType { self.dtype }
+
+  pub fn len(&self) -> usize { self.len }
+
+  pub fn is_empty(&self) -> bool { self.len == 0 }
+
+  pub fn is<T: Element>(&self) -> bool { self.buffer.is::<Buffer<T>>() }
+
+  fn type_mismatch<T: Element>(&self) -> Error {
+    Error::TypeMismatch {
+      expected: T::DATA_TYPE,
+      actual: self.dtype
+    }
+  }
+
+  pub fn downcast_ref<T: Element>(&self) -> Result<&Buffer<T>, Error> {
+    let mismatch = self.type_mismatch::<T>();
+    self.buffer.downcast_ref().ok_or(mismatch)
+  }
+
+  pub fn downcast_mut<T: Element>(&mut self) -> Result<&mut Buffer<T>, Error> {
+    let mismatch = self.type_mismatch::<T>();
+    self.buffer.downcast_mut().ok_or(mismatch)
+  }
+
+  /// Turns the buffer back into a `Buffer<T>`, handing it back untouched
+  /// if it holds another element type.
+  pub fn downcast<T: Element>(self) -> Result<Buffer<T>, AnyBuffer> {
+    let AnyBuffer { dtype, len, buffer } = self;
+    match buffer.downcast() {
+      Ok(buffer) => Ok(*buffer),
+      Err(buffer) => Err(AnyBuffer {
+        dtype: dtype,
+        len: len,
+        buffer: buffer
+      })
+    }
+  }
+}
+
+impl<T: Element> From<Buffer<T>> for AnyBuffer {
+  fn from(buffer: Buffer<T>) -> AnyBuffer { AnyBuffer::new(buffer) }
+}
+
+impl fmt::Debug for AnyBuffer {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "AnyBuffer {{ dtype: {}, len: {} }}", self.dtype, self.len)
+  }
+}
+
+// Range of `len` elements starting at `offset`, failing if it overflows
+// or reaches past `size`
+fn element_range(offset: usize, len: usize, size: usize) -> Result<Range<usize>, Error> {
+  match offset.checked_add(len) {
+    Some(end) if end <= size => Ok(offset..end),
+    end => Err(Error::OutOfRange {
+      start: offset,
+      end: end.unwrap_or(usize::max_value()),
+      len: size
+    })
+  }
+}
+
+// Adds `range` to a sorted list of disjoint ranges, merging it with any
+// range it overlaps or touches
+fn add_range(ranges: &mut Vec<Range<usize>>, range: Range<usize>) {
+  let mut merged = range;
+  let mut result = Vec::with_capacity(ranges.len() + 1);
+
+  for r in ranges.drain(..) {
+    if r.end < merged.start || r.start > merged.end {
+      result.push(r);
+    } else {
+      merged = cmp::min(r.start, merged.start)..cmp::max(r.end, merged.end);
+    }
+  }
+
+  result.push(merged);
+  result.sort_by_key(|r| r.start);
+  *ranges = result;
+}
@@ -0,0 +1,51 @@
+use std::error;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Bytes device copies move between two checks of their token.
+pub const COPY_CHUNK: usize = 1 << 20;
+
+/// Error of an operation that stopped because its token was cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "operation was cancelled")
+  }
+}
+
+impl error::Error for Cancelled { }
+
+/// Flag shared by everyone holding a clone. Operations it is attached to
+/// check it between steps and chunks of work and stop with `Cancelled`
+/// once it is set; work already running finishes its current chunk.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+  cancelled: Arc<AtomicBool>
+}
+
+impl CancelToken {
+  pub fn new() -> CancelToken {
+    CancelToken::default()
+  }
+
+  pub fn cancel(&self) {
+    self.cancelled.store(true, Ordering::SeqCst);
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.cancelled.load(Ordering::SeqCst)
+  }
+
+  /// Fails once the token is cancelled, so kernels can bail out with
+  /// `try!(token.check())`.
+  pub fn check(&self) -> Result<(), Cancelled> {
+    if self.is_cancelled() {
+      return Err(Cancelled)
+    }
+
+    Ok(())
+  }
+}
@@ -0,0 +1,64 @@
+use futures::{Future, IntoFuture};
+use half::{bf16, f16};
+use half::slice::HalfFloatSliceExt;
+
+use buffer::{Buffer, BufferDevice, BufferView, Error};
+use data_type::Element;
+use frameworks::native;
+
+/// 16-bit float types that can be converted to and from f32 in bulk.
+pub trait HalfFloat: Element {
+  fn to_f32_slice(src: &[Self], dst: &mut [f32]);
+  fn from_f32_slice(src: &[f32], dst: &mut [Self]);
+}
+
+impl HalfFloat for f16 {
+  fn to_f32_slice(src: &[f16], dst: &mut [f32]) { src.convert_to_f32_slice(dst) }
+  fn from_f32_slice(src: &[f32], dst: &mut [f16]) { dst.convert_from_f32_slice(src) }
+}
+
+impl HalfFloat for bf16 {
+  fn to_f32_slice(src: &[bf16], dst: &mut [f32]) { src.convert_to_f32_slice(dst) }
+  fn from_f32_slice(src: &[f32], dst: &mut [bf16]) { dst.convert_from_f32_slice(src) }
+}
+
+/// Widens `src` into `dst` on the pool of `dev`.
+pub fn to_f32<T: HalfFloat, V: Into<BufferView<T>>>(dev: &native::Device,
+                                                    src: V,
+                                                    dst: Buffer<f32>) -> Box<Future<Item=Buffer<f32>,Error=Error>> {
+  convert(dev, src.into(), dst, T::to_f32_slice)
+}
+
+/// Narrows `src` into `dst` on the pool of `dev`, rounding to nearest.
+pub fn from_f32<T: HalfFloat, V: Into<BufferView<f32>>>(dev: &native::Device,
+                                                        src: V,
+                                                        dst: Buffer<T>) -> Box<Future<Item=Buffer<T>,Error=Error>> {
+  convert(dev, src.into(), dst, T::from_f32_slice)
+}
+
+fn convert<S: Element, D: Element>(dev: &native::Device,
+                                   src: BufferView<S>,
+                                   dst: Buffer<D>,
+                                   f: fn(&[S], &mut [D])) -> Box<Future<Item=Buffer<D>,Error=Error>> {
+  if src.len() != dst.len() {
+    return Box::new(Err(Error::SizeMismatch {
+      expected: dst.len(),
+      actual: src.len()
+    }).into_future())
+  }
+
+  let bdev = BufferDevice::Native(dev.clone());
+  let ndev = dev.clone();
+  let pool = dev.pool().clone();
+  Box::new(src.sync(&bdev).join(dst.sync(&bdev)).and_then(move |(src, mut dst)| {
+    pool.spawn_fn(move || {
+      {
+        let src = try!(src.native_slice(&ndev));
+        let dst: &mut [D] = try!(try!(dst.native_memory_mut(&ndev)).try_as_mut_slice());
+        f(src, dst);
+      }
+
+      Ok(dst)
+    })
+  }))
+}
@@ -0,0 +1,208 @@
+use std::fmt;
+
+use half::{bf16, f16};
+use num_complex::{Complex32, Complex64};
+
+/// Element type of a buffer, known at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DataType {
+  Bool,
+  U8,
+  U16,
+  U32,
+  U64,
+  I8,
+  I16,
+  I32,
+  I64,
+  F16,
+  BF16,
+  F32,
+  F64,
+  C64,
+  C128
+}
+
+// Keeps `Element` to the types below, formats trust `DATA_TYPE` to
+// reinterpret bytes as the type
+mod private {
+  pub trait Sealed { }
+}
+
+/// Element types buffers can be created with at runtime. Sealed, it is
+/// only implemented for the types `DataType` lists.
+pub trait Element: private::Sealed + Copy + Send + Sync + 'static {
+  const DATA_TYPE: DataType;
+
+  /// Whether `bytes` only hold valid values of the type.
+  fn validate(_bytes: &[u8]) -> bool { true }
+}
+
+macro_rules! element {
+  ($t:ty, $dtype:ident) => {
+    impl private::Sealed for $t { }
+
+    impl Element for $t {
+      const DATA_TYPE: DataType = DataType::$dtype;
+    }
+  }
+}
+
+element!(u8, U8);
+element!(u16, U16);
+element!(u32, U32);
+element!(u64, U64);
+element!(i8, I8);
+element!(i16, I16);
+element!(i32, I32);
+element!(i64, I64);
+element!(f16, F16);
+element!(bf16, BF16);
+element!(f32, F32);
+element!(f64, F64);
+element!(Complex32, C64);
+element!(Complex64, C128);
+
+impl private::Sealed for bool { }
+
+impl Element for bool {
+  const DATA_TYPE: DataType = DataType::Bool;
+
+  fn validate(bytes: &[u8]) -> bool { bytes.iter().all(|&b| b <= 1) }
+}
+
+// Calls the generic function `$f` with the element type of `$dtype`
+macro_rules! with_element_type {
+  ($dtype:expr, $f:ident($($arg:expr),*)) => {
+    match $dtype {
+      DataType::Bool => $f::<bool>($($arg),*),
+      DataType::U8 => $f::<u8>($($arg),*),
+      DataType::U16 => $f::<u16>($($arg),*),
+      DataType::U32 => $f::<u32>($($arg),*),
+      DataType::U64 => $f::<u64>($($arg),*),
+      DataType::I8 => $f::<i8>($($arg),*),
+      DataType::I16 => $f::<i16>($($arg),*),
+      DataType::I32 => $f::<i32>($($arg),*),
+      DataType::I64 => $f::<i64>($($arg),*),
+      DataType::F16 => $f::<::half::f16>($($arg),*),
+      DataType::BF16 => $f::<::half::bf16>($($arg),*),
+      DataType::F32 => $f::<f32>($($arg),*),
+      DataType::F64 => $f::<f64>($($arg),*),
+      DataType::C64 => $f::<::num_complex::Complex32>($($arg),*),
+      DataType::C128 => $f::<::num_complex::Complex64>($($arg),*)
+    }
+  }
+}
+
+const DATA_TYPES: &'static [DataType] = &[
+  DataType::Bool,
+  DataType::U8, DataType::U16, DataType::U32, DataType::U64,
+  DataType::I8, DataType::I16, DataType::I32, DataType::I64,
+  DataType::F16, DataType::BF16, DataType::F32, DataType::F64,
+  DataType::C64, DataType::C128
+];
+
+impl DataType {
+  pub fn all() -> &'static [DataType] { DATA_TYPES }
+
+  /// Size of one element in bytes.
+  pub fn size(&self) -> usize {
+    match *self {
+      DataType::Bool | DataType::U8 | DataType::I8 => 1,
+      DataType::U16 | DataType::I16 | DataType::F16 | DataType::BF16 => 2,
+      DataType::U32 | DataType::I32 | DataType::F32 => 4,
+      DataType::U64 | DataType::I64 | DataType::F64 | DataType::C64 => 8,
+      DataType::C128 => 16
+    }
+  }
+
+  pub fn is_complex(&self) -> bool {
+    *self == DataType::C64 || *self == DataType::C128
+  }
+
+  /// Size of the scalars an element is made of, which is what byte order
+  /// applies to. Complex elements are a pair of floats.
+  pub fn scalar_size(&self) -> usize {
+    if self.is_complex() { self.size() / 2 } else { self.size() }
+  }
+
+  pub fn name(&self) -> &'static str {
+    match *self {
+      DataType::Bool => "bool",
+      DataType::U8 => "u8",
+      DataType::U16 => "u16",
+      DataType::U32 => "u32",
+      DataType::U64 => "u64",
+      DataType::I8 => "i8",
+      DataType::I16 => "i16",
+      DataType::I32 => "i32",
+      DataType::I64 => "i64",
+      DataType::F16 => "f16",
+      DataType::BF16 => "bf16",
+      DataType::F32 => "f32",
+      DataType::F64 => "f64",
+      DataType::C64 => "c64",
+      DataType::C128 => "c128"
+    }
+  }
+
+  /// NPY type code, without the byte order. NumPy has no bf16.
+  pub fn npy_code(&self) -> Option<&'static str> {
+    let code = match *self {
+      DataType::Bool => "b1",
+      DataType::U8 => "u1",
+      DataType::U16 => "u2",
+      DataType::U32 => "u4",
+      DataType::U64 => "u8",
+      DataType::I8 => "i1",
+      DataType::I16 => "i2",
+      DataType::I32 => "i4",
+      DataType::I64 => "i8",
+      DataType::F16 => "f2",
+      DataType::BF16 => return None,
+      DataType::F32 => "f4",
+      DataType::F64 => "f8",
+      DataType::C64 => "c8",
+      DataType::C128 => "c16"
+    };
+
+    Some(code)
+  }
+
+  pub fn from_npy_code(code: &str) -> Option<DataType> {
+    DATA_TYPES.iter().cloned().find(|dtype| dtype.npy_code() == Some(code))
+  }
+
+  /// Name of the dtype in safetensors headers, which have no complex128.
+  pub fn safetensors_name(&self) -> Option<&'static str> {
+    let name = match *self {
+      DataType::Bool => "BOOL",
+      DataType::U8 => "U8",
+      DataType::U16 => "U16",
+      DataType::U32 => "U32",
+      DataType::U64 => "U64",
+      DataType::I8 => "I8",
+      DataType::I16 => "I16",
+      DataType::I32 => "I32",
+      DataType::I64 => "I64",
+      DataType::F16 => "F16",
+      DataType::BF16 => "BF16",
+      DataType::F32 => "F32",
+      DataType::F64 => "F64",
+      DataType::C64 => "C64",
+      DataType::C128 => return None
+    };
+
+    Some(name)
+  }
+
+  pub fn from_safetensors_name(name: &str) -> Option<DataType> {
+    DATA_TYPES.iter().cloned().find(|dtype| dtype.safetensors_name() == Some(name))
+  }
+}
+
+impl fmt::Display for DataType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.name())
+  }
+}
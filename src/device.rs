@@ -1,9 +1,11 @@
 use std::fmt;
+use std::ops::Range;
 
 use futures::Future;
 
 use hardware::Hardware;
 use memory::Memory;
+use stream::Stream;
 
 pub trait Device {
   type H: Hardware;
@@ -13,10 +15,29 @@ pub trait Device {
   fn id(&self) -> isize;
   fn hardware(&self) -> &Self::H;
   fn alloc_memory(&self, size: usize) -> Result<Self::M, Self::Error>;
+  fn memory_from_vec<T: Send + Copy + Sized + 'static>(&self,
+                                                       vec: Vec<T>) -> Result<Self::M, Self::Error>;
   fn sync_from_vec<T: Send + Copy + Sized + 'static>(&self,
                                                      mem: Self::M,
                                                      vec: Vec<T>) -> Box<Future<Item=Self::M,Error=Self::Error>>;
 
   fn sync_to_vec<T: Send + Copy + Sized + 'static>(&self,
                                                    mem: Self::M) -> Box<Future<Item=(Self::M, Vec<T>),Error=Self::Error>>;
+
+  // Offsets and ranges are in elements of T
+  fn write_range<T: Send + Copy + Sized + 'static>(&self,
+                                                   mem: Self::M,
+                                                   offset: usize,
+                                                   vec: Vec<T>) -> Box<Future<Item=Self::M,Error=Self::Error>>;
+
+  fn read_range<T: Send + Copy + Sized + 'static>(&self,
+                                                  mem: Self::M,
+                                                  range: Range<usize>) -> Box<Future<Item=(Self::M, Vec<T>),Error=Self::Error>>;
+
+  fn into_vec<T: Send + Copy + Sized + 'static>(&self,
+                                                mem: Self::M) -> Box<Future<Item=Vec<T>,Error=Self::Error>>;
+
+  // Operations on one stream run in order, streams run side by side.
+  // Each stream starts a thread of its own outside the device pool
+  fn new_stream(&self) -> Stream;
 }
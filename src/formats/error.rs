@@ -0,0 +1,90 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use zip::result::ZipError;
+
+use buffer;
+use data_type::DataType;
+
+#[derive(Debug)]
+pub enum Error {
+  Io(io::Error),
+
+  Buffer(buffer::Error),
+
+  Zip(ZipError),
+
+  // File does not start with the magic string of its format
+  InvalidMagic,
+
+  // Format version this reader does not know
+  UnsupportedVersion { major: u8, minor: u8 },
+
+  // Header could not be parsed
+  InvalidHeader(String),
+
+  // Element type in the file has no popcorn equivalent
+  UnsupportedDtype(String),
+
+  // Element type in the file differs from the one asked for
+  DtypeMismatch { expected: DataType, actual: DataType },
+
+  // Data section does not hold the number of bytes the header promises
+  SizeMismatch { expected: usize, actual: usize },
+
+  // Data holds values that are not valid for its dtype
+  InvalidData(String),
+
+  // Data offsets of an array reach past the data or overlap another array
+  InvalidOffsets { name: String, start: usize, end: usize },
+
+  // No array with this name in the archive
+  MissingArray(String)
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Error::Io(_) => write!(f, "I/O error"),
+      Error::Buffer(_) => write!(f, "buffer error"),
+      Error::Zip(_) => write!(f, "zip archive error"),
+      Error::InvalidMagic => write!(f, "invalid magic string"),
+      Error::UnsupportedVersion { major, minor } =>
+        write!(f, "unsupported format version {}.{}", major, minor),
+      Error::InvalidHeader(ref msg) => write!(f, "invalid header: {}", msg),
+      Error::UnsupportedDtype(ref dtype) => write!(f, "unsupported dtype {}", dtype),
+      Error::DtypeMismatch { expected, actual } =>
+        write!(f, "dtype mismatch: expected {}, got {}", expected, actual),
+      Error::SizeMismatch { expected, actual } =>
+        write!(f, "size mismatch: expected {} bytes of data, got {}", expected, actual),
+      Error::InvalidData(ref dtype) => write!(f, "data is not valid {}", dtype),
+      Error::InvalidOffsets { ref name, start, end } =>
+        write!(f, "invalid data offsets {}..{} for {:?}", start, end, name),
+      Error::MissingArray(ref name) => write!(f, "no array named {:?}", name)
+    }
+  }
+}
+
+impl error::Error for Error {
+  fn source(&self) -> Option<&(error::Error + 'static)> {
+    match *self {
+      Error::Io(ref err) => Some(err),
+      Error::Buffer(ref err) => Some(err),
+      Error::Zip(ref err) => Some(err),
+      _ => None
+    }
+  }
+}
+
+impl From<io::Error> for Error {
+  fn from(err: io::Error) -> Error { Error::Io(err) }
+}
+
+impl From<buffer::Error> for Error {
+  fn from(err: buffer::Error) -> Error { Error::Buffer(err) }
+}
+
+impl From<ZipError> for Error {
+  fn from(err: ZipError) -> Error { Error::Zip(err) }
+}
@@ -0,0 +1,33 @@
+mod error;
+pub mod npy;
+pub mod npz;
+pub mod safetensors;
+
+use std::mem;
+use std::slice;
+
+use buffer::{self, BufferDevice};
+use data_type::Element;
+use frameworks::native;
+use tensor::Tensor;
+
+pub use self::error::Error;
+
+// Contents of `tensor` on `dev`, which has to be up to date
+fn host_data<'a, T: Element>(tensor: &'a Tensor<T>, dev: &native::Device) -> Result<&'a [T], Error> {
+  let bdev = BufferDevice::Native(dev.clone());
+  if !tensor.buffer().is_latest(&bdev) {
+    return Err(Error::Buffer(buffer::Error::StaleCopy(bdev)))
+  }
+
+  let mem = try!(tensor.buffer().native_memory(dev));
+  Ok(try!(mem.try_as_slice().map_err(buffer::Error::Native)))
+}
+
+fn as_bytes<T: Copy>(data: &[T]) -> &[u8] {
+  unsafe { slice::from_raw_parts(data.as_ptr() as *const u8, data.len() * mem::size_of::<T>()) }
+}
+
+fn as_mut_bytes<T: Copy>(data: &mut [T]) -> &mut [u8] {
+  unsafe { slice::from_raw_parts_mut(data.as_mut_ptr() as *mut u8, data.len() * mem::size_of::<T>()) }
+}
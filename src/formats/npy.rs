@@ -0,0 +1,457 @@
+use std::cmp;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::iter::Peekable;
+use std::mem;
+use std::path::Path;
+use std::str::Chars;
+
+use buffer::{self, AnyBuffer};
+use frameworks::native;
+use tensor::Tensor;
+use data_type::{DataType, Element};
+use super::{Error, as_bytes, as_mut_bytes, host_data};
+
+const MAGIC: &'static [u8] = b"\x93NUMPY";
+
+// Headers are padded so the data starts at a multiple of this
+const HEADER_ALIGN: usize = 64;
+
+// Largest header accepted, so a corrupt length cannot make us allocate
+// gigabytes before reading a byte of it
+const MAX_HEADER_SIZE: usize = 1 << 20;
+
+// Data of inputs with unknown length is read in chunks of this many
+// bytes, so a header cannot make us allocate much more than there is
+const READ_CHUNK: usize = 1 << 20;
+
+/// Header of an NPY file, describing the array that follows it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+  // Dtype descriptor, byte order followed by the type code
+  pub descr: String,
+
+  // Whether the data is laid out in column-major order
+  pub fortran_order: bool,
+
+  pub shape: Vec<usize>
+}
+
+impl Header {
+  /// Header for a C-ordered array of `T` in native byte order. Fails
+  /// for element types NumPy has no dtype for.
+  pub fn new<T: Element>(shape: Vec<usize>) -> Result<Header, Error> {
+    let code = match T::DATA_TYPE.npy_code() {
+      Some(code) => code,
+      None => return Err(Error::UnsupportedDtype(T::DATA_TYPE.to_string()))
+    };
+
+    let order = if mem::size_of::<T>() == 1 {
+      '|'
+    } else if cfg!(target_endian = "little") {
+      '<'
+    } else {
+      '>'
+    };
+
+    Ok(Header {
+      descr: format!("{}{}", order, code),
+      fortran_order: false,
+      shape: shape
+    })
+  }
+
+  pub fn len(&self) -> usize { self.shape.iter().product() }
+
+  pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+  pub fn type_code(&self) -> &str {
+    match self.descr.chars().next() {
+      Some('<') | Some('>') | Some('|') | Some('=') => &self.descr[1..],
+      _ => &self.descr
+    }
+  }
+
+  pub fn data_type(&self) -> Result<DataType, Error> {
+    DataType::from_npy_code(self.type_code()).
+      ok_or_else(|| Error::UnsupportedDtype(self.descr.clone()))
+  }
+
+  /// Checks that the array holds elements of `T` and tells whether they
+  /// have to be byte swapped.
+  pub fn check_dtype<T: Element>(&self) -> Result<bool, Error> {
+    let dtype = try!(self.data_type());
+    if dtype != T::DATA_TYPE {
+      return Err(Error::DtypeMismatch {
+        expected: T::DATA_TYPE,
+        actual: dtype
+      })
+    }
+
+    let swapped = match self.descr.chars().next() {
+      Some('<') => cfg!(target_endian = "big"),
+      Some('>') => cfg!(target_endian = "little"),
+      _ => false
+    };
+
+    Ok(swapped && T::DATA_TYPE.scalar_size() > 1)
+  }
+
+  pub fn read<R: Read>(r: &mut R) -> Result<Header, Error> {
+    Header::read_counted(r, None).map(|(header, _)| header)
+  }
+
+  /// Bytes of data the array takes, failing if the shape overflows.
+  pub fn data_size(&self, elem_size: usize) -> Result<usize, Error> {
+    self.shape.iter().
+      try_fold(elem_size, |size, &dim| size.checked_mul(dim)).
+      ok_or_else(|| Error::InvalidHeader(format!("shape {:?} is too large", self.shape)))
+  }
+
+  // Also returns the number of bytes the header took. Inputs of known
+  // `size` fail before allocating a header longer than they are
+  fn read_counted<R: Read + ?Sized>(r: &mut R, size: Option<u64>) -> Result<(Header, usize), Error> {
+    let mut magic = [0u8; 8];
+    try!(r.read_exact(&mut magic));
+    if &magic[..6] != MAGIC {
+      return Err(Error::InvalidMagic)
+    }
+
+    let (len, len_size) = match (magic[6], magic[7]) {
+      (1, 0) => {
+        let mut len = [0u8; 2];
+        try!(r.read_exact(&mut len));
+        (u16::from_le_bytes(len) as usize, 2)
+      },
+      (2, 0) | (3, 0) => {
+        let mut len = [0u8; 4];
+        try!(r.read_exact(&mut len));
+        (u32::from_le_bytes(len) as usize, 4)
+      },
+      (major, minor) => return Err(Error::UnsupportedVersion {
+        major: major,
+        minor: minor
+      })
+    };
+
+    let header_size = magic.len() + len_size + len;
+    if len > MAX_HEADER_SIZE || size.map_or(false, |size| header_size as u64 > size) {
+      return Err(Error::InvalidHeader(format!("header length {} is out of bounds", len)))
+    }
+
+    let mut header = vec![0u8; len];
+    try!(r.read_exact(&mut header));
+    let header = try!(String::from_utf8(header).
+                      map_err(|_| Error::InvalidHeader("header is not valid text".to_string())));
+
+    let header = try!(Parser::new(&header).header());
+    Ok((header, header_size))
+  }
+
+  pub fn write<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+    let shape: Vec<String> = self.shape.iter().map(|d| d.to_string()).collect();
+    let shape = if shape.len() == 1 {
+      format!("({},)", shape[0])
+    } else {
+      format!("({})", shape.join(", "))
+    };
+
+    let mut dict = format!("{{'descr': '{}', 'fortran_order': {}, 'shape': {}, }}",
+                           self.descr,
+                           if self.fortran_order { "True" } else { "False" },
+                           shape);
+
+    // Version 1 only has room for a 16 bit header length
+    let (version, len_size) = if dict.len() + HEADER_ALIGN < u16::max_value() as usize {
+      (1, 2)
+    } else {
+      (2, 4)
+    };
+
+    let unpadded = MAGIC.len() + 2 + len_size + dict.len() + 1;
+    let padding = (HEADER_ALIGN - unpadded % HEADER_ALIGN) % HEADER_ALIGN;
+    dict.extend((0..padding).map(|_| ' '));
+    dict.push('\n');
+
+    try!(w.write_all(MAGIC));
+    try!(w.write_all(&[version, 0]));
+    if version == 1 {
+      try!(w.write_all(&(dict.len() as u16).to_le_bytes()));
+    } else {
+      try!(w.write_all(&(dict.len() as u32).to_le_bytes()));
+    }
+    try!(w.write_all(dict.as_bytes()));
+
+    Ok(())
+  }
+}
+
+/// Reads an NPY array of `T` into a tensor on `dev`. Data in the other
+/// byte order or in Fortran order is converted on the way in.
+pub fn read<R: Read, T: Element>(r: &mut R, dev: &native::Device) -> Result<Tensor<T>, Error> {
+  read_sized(r, None, dev)
+}
+
+/// Reads an NPY array whose element type is only known from its header,
+/// returning the buffer along with its shape.
+pub fn read_any<R: Read>(r: &mut R, dev: &native::Device) -> Result<(AnyBuffer, Vec<usize>), Error> {
+  let header = try!(Header::read(r));
+  let dtype = try!(header.data_type());
+  with_element_type!(dtype, read_any_data(r, header, dev))
+}
+
+// Reads an array from an input of `size` bytes, header included. Headers
+// promising more data than that fail before anything is allocated.
+pub(crate) fn read_sized<R: Read + ?Sized, T: Element>(r: &mut R,
+                                                      size: Option<u64>,
+                                                      dev: &native::Device) -> Result<Tensor<T>, Error> {
+  let (header, header_size) = try!(Header::read_counted(r, size));
+  let remaining = size.map(|size| size.saturating_sub(header_size as u64));
+  read_data(r, header, remaining, dev)
+}
+
+fn read_any_data<T: Element>(r: &mut Read, header: Header, dev: &native::Device) -> Result<(AnyBuffer, Vec<usize>), Error> {
+  let tensor: Tensor<T> = try!(read_data(r, header, None, dev));
+  let shape = tensor.dims().to_vec();
+  Ok((tensor.into_buffer().into(), shape))
+}
+
+fn read_data<R: Read + ?Sized, T: Element>(r: &mut R,
+                                           header: Header,
+                                           remaining: Option<u64>,
+                                           dev: &native::Device) -> Result<Tensor<T>, Error> {
+  let swapped = try!(header.check_dtype::<T>());
+  let size = mem::size_of::<T>();
+  let expected = try!(header.data_size(size));
+  if let Some(remaining) = remaining {
+    if (expected as u64) > remaining {
+      return Err(Error::SizeMismatch {
+        expected: expected,
+        actual: remaining as usize
+      })
+    }
+  }
+
+  // Every supported element type is valid when zeroed. The data grows
+  //   chunk by chunk as it comes in
+  let len = expected / size;
+  let chunk = cmp::max(READ_CHUNK / size, 1);
+  let mut data: Vec<T> = Vec::new();
+  while data.len() < len {
+    let start = data.len();
+    data.resize(cmp::min(start + chunk, len), unsafe { mem::zeroed() });
+    let bytes = as_mut_bytes(&mut data[start..]);
+    let read = try!(read_full(r, bytes));
+    if read != bytes.len() {
+      return Err(Error::SizeMismatch {
+        expected: expected,
+        actual: start * size + read
+      })
+    }
+  }
+
+  {
+    let bytes = as_mut_bytes(&mut data);
+    if !T::validate(bytes) {
+      return Err(Error::InvalidData(header.descr.clone()))
+    }
+
+    if swapped {
+      for elem in bytes.chunks_mut(T::DATA_TYPE.scalar_size()) {
+        elem.reverse();
+      }
+    }
+  }
+
+  if header.fortran_order {
+    data = fortran_to_c(&data, &header.shape);
+  }
+
+  Ok(try!(Tensor::from_vec(dev, data, header.shape)))
+}
+
+/// Writes a C-ordered NPY array that NumPy can load.
+pub fn write<W: Write, T: Element>(w: &mut W, shape: &[usize], data: &[T]) -> Result<(), Error> {
+  let header = try!(Header::new::<T>(shape.to_vec()));
+  if header.len() != data.len() {
+    return Err(Error::Buffer(buffer::Error::InvalidShape {
+      shape: shape.to_vec(),
+      len: data.len()
+    }))
+  }
+
+  try!(header.write(w));
+  try!(w.write_all(as_bytes(data)));
+  Ok(())
+}
+
+pub fn load<P: AsRef<Path>, T: Element>(path: P, dev: &native::Device) -> Result<Tensor<T>, Error> {
+  let file = try!(File::open(path));
+  let size = try!(file.metadata()).len();
+  read_sized(&mut BufReader::new(file), Some(size), dev)
+}
+
+/// Saves `tensor` as an NPY file. Its copy on `dev` has to be up to date.
+pub fn save<P: AsRef<Path>, T: Element>(path: P, tensor: &Tensor<T>, dev: &native::Device) -> Result<(), Error> {
+  let data = try!(host_data(tensor, dev));
+  let mut w = BufWriter::new(try!(File::create(path)));
+  try!(write(&mut w, tensor.dims(), data));
+  try!(w.flush());
+  Ok(())
+}
+
+// Like read_exact, but reports how many bytes were read before the
+// end of the input
+fn read_full<R: Read + ?Sized>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+  let mut read = 0;
+  while read < buf.len() {
+    match r.read(&mut buf[read..]) {
+      Ok(0) => break,
+      Ok(n) => read += n,
+      Err(ref err) if err.kind() == io::ErrorKind::Interrupted => { },
+      Err(err) => return Err(err)
+    }
+  }
+
+  Ok(read)
+}
+
+fn fortran_to_c<T: Copy>(data: &[T], shape: &[usize]) -> Vec<T> {
+  let mut f_strides = vec![1; shape.len()];
+  for i in 1..shape.len() {
+    f_strides[i] = f_strides[i - 1] * shape[i - 1];
+  }
+
+  (0..data.len()).map(|mut index| {
+    let mut offset = 0;
+    for (&dim, &stride) in shape.iter().zip(f_strides.iter()).rev() {
+      offset += (index % dim) * stride;
+      index /= dim;
+    }
+    data[offset]
+  }).collect()
+}
+
+enum Value {
+  Str(String),
+  Bool(bool),
+  Tuple(Vec<usize>)
+}
+
+// Parser for the Python dict literal in NPY headers
+struct Parser<'a> {
+  chars: Peekable<Chars<'a>>
+}
+
+impl<'a> Parser<'a> {
+  fn new(s: &'a str) -> Parser<'a> {
+    Parser {
+      chars: s.chars().peekable()
+    }
+  }
+
+  fn error<V>(msg: &str) -> Result<V, Error> {
+    Err(Error::InvalidHeader(msg.to_string()))
+  }
+
+  fn skip_whitespace(&mut self) {
+    while self.chars.peek().map_or(false, |c| c.is_whitespace()) {
+      self.chars.next();
+    }
+  }
+
+  // Consumes `c` if it is the next character
+  fn eat(&mut self, c: char) -> bool {
+    self.skip_whitespace();
+    if self.chars.peek() == Some(&c) {
+      self.chars.next();
+      true
+    } else {
+      false
+    }
+  }
+
+  fn expect(&mut self, c: char) -> Result<(), Error> {
+    if self.eat(c) { Ok(()) } else { Self::error(&format!("expected {:?}", c)) }
+  }
+
+  fn header(&mut self) -> Result<Header, Error> {
+    let mut descr = None;
+    let mut fortran_order = None;
+    let mut shape = None;
+
+    try!(self.expect('{'));
+    while !self.eat('}') {
+      let key = match try!(self.value()) {
+        Value::Str(key) => key,
+        _ => return Self::error("expected a string key")
+      };
+      try!(self.expect(':'));
+
+      match (key.as_str(), try!(self.value())) {
+        ("descr", Value::Str(v)) => descr = Some(v),
+        ("fortran_order", Value::Bool(v)) => fortran_order = Some(v),
+        ("shape", Value::Tuple(v)) => shape = Some(v),
+        (key, _) => return Self::error(&format!("unexpected entry {:?}", key))
+      }
+
+      if !self.eat(',') {
+        try!(self.expect('}'));
+        break
+      }
+    }
+
+    match (descr, fortran_order, shape) {
+      (Some(descr), Some(fortran_order), Some(shape)) => Ok(Header {
+        descr: descr,
+        fortran_order: fortran_order,
+        shape: shape
+      }),
+      _ => Self::error("missing descr, fortran_order or shape")
+    }
+  }
+
+  fn value(&mut self) -> Result<Value, Error> {
+    self.skip_whitespace();
+    match self.chars.peek().cloned() {
+      Some(quote) if quote == '\'' || quote == '"' => {
+        self.chars.next();
+        let s: String = self.chars.by_ref().take_while(|&c| c != quote).collect();
+        Ok(Value::Str(s))
+      },
+      Some('(') => {
+        self.chars.next();
+        let mut dims = Vec::new();
+        while !self.eat(')') {
+          self.skip_whitespace();
+          let mut digits = String::new();
+          while self.chars.peek().map_or(false, |c| c.is_digit(10)) {
+            digits.push(self.chars.next().unwrap());
+          }
+          match digits.parse() {
+            Ok(dim) => dims.push(dim),
+            Err(_) => return Self::error("expected a dimension")
+          }
+
+          if !self.eat(',') {
+            try!(self.expect(')'));
+            break
+          }
+        }
+        Ok(Value::Tuple(dims))
+      },
+      Some(c) if c.is_alphabetic() => {
+        let mut word = String::new();
+        while self.chars.peek().map_or(false, |c| c.is_alphabetic()) {
+          word.push(self.chars.next().unwrap());
+        }
+        match word.as_str() {
+          "True" => Ok(Value::Bool(true)),
+          "False" => Ok(Value::Bool(false)),
+          _ => Self::error(&format!("unexpected {:?}", word))
+        }
+      },
+      _ => Self::error("expected a value")
+    }
+  }
+}
@@ -0,0 +1,120 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::mem;
+use std::path::Path;
+
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+use zip::read::ZipFile;
+use zip::result::ZipError;
+use zip::write::FileOptions;
+
+use frameworks::native;
+use tensor::Tensor;
+use data_type::Element;
+use super::{Error, host_data};
+use super::npy::{self, Header};
+
+/// Reads arrays from an NPZ archive, as written by `numpy.savez` and
+/// `numpy.savez_compressed`.
+pub struct NpzReader<R: Read + Seek> {
+  archive: ZipArchive<R>
+}
+
+/// Writes arrays into an NPZ archive that `numpy.load` can open.
+pub struct NpzWriter<W: Write + Seek> {
+  zip: ZipWriter<W>,
+  compression: CompressionMethod
+}
+
+// Arrays are stored as NPY files named after them
+fn file_name(name: &str) -> String {
+  if name.ends_with(".npy") {
+    name.to_string()
+  } else {
+    format!("{}.npy", name)
+  }
+}
+
+impl NpzReader<BufReader<File>> {
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<NpzReader<BufReader<File>>, Error> {
+    NpzReader::new(BufReader::new(try!(File::open(path))))
+  }
+}
+
+impl<R: Read + Seek> NpzReader<R> {
+  pub fn new(reader: R) -> Result<NpzReader<R>, Error> {
+    Ok(NpzReader {
+      archive: try!(ZipArchive::new(reader))
+    })
+  }
+
+  /// Names of the arrays in the archive.
+  pub fn names(&self) -> Vec<String> {
+    self.archive.file_names().
+      filter(|name| name.ends_with(".npy")).
+      map(|name| name[..name.len() - 4].to_string()).
+      collect()
+  }
+
+  pub fn header(&mut self, name: &str) -> Result<Header, Error> {
+    let mut file = try!(self.by_name(name));
+    Header::read(&mut file)
+  }
+
+  pub fn read<T: Element>(&mut self, name: &str, dev: &native::Device) -> Result<Tensor<T>, Error> {
+    let mut file = try!(self.by_name(name));
+    let size = file.size();
+    npy::read_sized(&mut file, Some(size), dev)
+  }
+
+  fn by_name<'a>(&'a mut self, name: &str) -> Result<ZipFile<'a>, Error> {
+    match self.archive.by_name(&file_name(name)) {
+      Ok(file) => Ok(file),
+      Err(ZipError::FileNotFound) => Err(Error::MissingArray(name.to_string())),
+      Err(err) => Err(Error::Zip(err))
+    }
+  }
+}
+
+impl NpzWriter<BufWriter<File>> {
+  pub fn create<P: AsRef<Path>>(path: P) -> Result<NpzWriter<BufWriter<File>>, Error> {
+    Ok(NpzWriter::new(BufWriter::new(try!(File::create(path)))))
+  }
+}
+
+impl<W: Write + Seek> NpzWriter<W> {
+  pub fn new(writer: W) -> NpzWriter<W> {
+    NpzWriter {
+      zip: ZipWriter::new(writer),
+      compression: CompressionMethod::Stored
+    }
+  }
+
+  /// Deflates arrays like `numpy.savez_compressed` does.
+  pub fn compressed(mut self) -> NpzWriter<W> {
+    self.compression = CompressionMethod::Deflated;
+    self
+  }
+
+  pub fn write<T: Element>(&mut self, name: &str, shape: &[usize], data: &[T]) -> Result<(), Error> {
+    let options = FileOptions::default().
+      compression_method(self.compression).
+      large_file(data.len() * mem::size_of::<T>() >= u32::max_value() as usize);
+
+    try!(self.zip.start_file(file_name(name), options));
+    npy::write(&mut self.zip, shape, data)
+  }
+
+  /// Adds `tensor` to the archive. Its copy on `dev` has to be up to date.
+  pub fn add<T: Element>(&mut self, name: &str, tensor: &Tensor<T>, dev: &native::Device) -> Result<(), Error> {
+    let data = try!(host_data(tensor, dev));
+    self.write(name, tensor.dims(), data)
+  }
+
+  /// Writes the archive directory and hands back the writer.
+  pub fn finish(mut self) -> Result<W, Error> {
+    let mut writer = try!(self.zip.finish());
+    try!(writer.flush());
+    Ok(writer)
+  }
+}
@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::Arc;
+
+use memmap2::Mmap;
+use serde_json::{self, Map, Value};
+
+use buffer::{self, AnyBuffer, Buffer, BufferDevice, RawBuffer};
+use frameworks::native;
+use tensor::Tensor;
+use data_type::{DataType, Element};
+use super::{Error, as_bytes, as_mut_bytes, host_data};
+
+// Largest header accepted, so a corrupt length cannot make us parse
+// the whole file as JSON
+const MAX_HEADER_SIZE: usize = 100 * 1024 * 1024;
+
+// Headers are padded so the data starts at a multiple of this
+const HEADER_ALIGN: usize = 8;
+
+// Every dtype of the format with its size in bytes
+const DTYPES: &'static [(&'static str, usize)] = &[
+  ("BOOL", 1), ("U8", 1), ("I8", 1), ("F8_E5M2", 1), ("F8_E4M3", 1),
+  ("U16", 2), ("I16", 2), ("F16", 2), ("BF16", 2),
+  ("U32", 4), ("I32", 4), ("F32", 4),
+  ("U64", 8), ("I64", 8), ("F64", 8), ("C64", 8)
+];
+
+/// Where a tensor lives in a safetensors file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TensorInfo {
+  pub dtype: String,
+  pub shape: Vec<usize>,
+
+  // Byte range of the tensor, relative to the start of the data
+  pub data_offsets: (usize, usize)
+}
+
+/// Safetensors file mapped into memory. Tensors are only read once they
+/// are asked for, and native devices use the mapped data in place.
+pub struct SafeTensors {
+  mmap: Arc<Mmap>,
+  data_start: usize,
+  tensors: HashMap<String, TensorInfo>,
+  metadata: HashMap<String, String>
+}
+
+/// Collects named tensors and writes them out as a safetensors file.
+pub struct SafeTensorsWriter<'a> {
+  entries: Vec<Entry<'a>>,
+  metadata: Map<String, Value>
+}
+
+struct Entry<'a> {
+  name: String,
+  dtype: &'static str,
+  shape: Vec<usize>,
+  // Unit the byte order applies to
+  scalar_size: usize,
+  data: &'a [u8]
+}
+
+impl SafeTensors {
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<SafeTensors, Error> {
+    let file = try!(File::open(path));
+    let mmap = unsafe { try!(Mmap::map(&file)) };
+    Self::from_mmap(Arc::new(mmap))
+  }
+
+  /// Parses and validates the header of a mapped file.
+  pub fn from_mmap(mmap: Arc<Mmap>) -> Result<SafeTensors, Error> {
+    if mmap.len() < 8 {
+      return Err(Error::InvalidHeader("file is too short".to_string()))
+    }
+
+    let mut len = [0u8; 8];
+    len.copy_from_slice(&mmap[..8]);
+    let len = u64::from_le_bytes(len);
+    if len > MAX_HEADER_SIZE as u64 || len as usize > mmap.len() - 8 {
+      return Err(Error::InvalidHeader(format!("header length {} is out of bounds", len)))
+    }
+
+    let data_start = 8 + len as usize;
+    let header: Value = try!(serde_json::from_slice(&mmap[8..data_start]).
+                             map_err(|err| Error::InvalidHeader(err.to_string())));
+    let entries = match header {
+      Value::Object(entries) => entries,
+      _ => return Err(Error::InvalidHeader("header is not a JSON object".to_string()))
+    };
+
+    let data_len = mmap.len() - data_start;
+    let mut tensors = HashMap::new();
+    let mut metadata = HashMap::new();
+    for (name, entry) in entries {
+      if name == "__metadata__" {
+        metadata = try!(parse_metadata(entry));
+      } else {
+        let info = try!(parse_info(&name, &entry, data_len));
+        tensors.insert(name, info);
+      }
+    }
+
+    // Tensors have to cover the data without holes or overlaps
+    let mut ranges: Vec<(&String, &TensorInfo)> = tensors.iter().collect();
+    ranges.sort_by_key(|&(_, info)| info.data_offsets);
+    let mut end = 0;
+    for (name, info) in ranges {
+      let (start, next) = info.data_offsets;
+      if start != end {
+        return Err(Error::InvalidOffsets {
+          name: name.clone(),
+          start: start,
+          end: next
+        })
+      }
+      end = next;
+    }
+    if end != data_len {
+      return Err(Error::SizeMismatch {
+        expected: end,
+        actual: data_len
+      })
+    }
+
+    Ok(SafeTensors {
+      mmap: mmap,
+      data_start: data_start,
+      tensors: tensors,
+      metadata: metadata
+    })
+  }
+
+  pub fn names(&self) -> Vec<&str> {
+    self.tensors.keys().map(|name| name.as_str()).collect()
+  }
+
+  pub fn metadata(&self) -> &HashMap<String, String> { &self.metadata }
+
+  pub fn info(&self, name: &str) -> Result<&TensorInfo, Error> {
+    self.tensors.get(name).ok_or_else(|| Error::MissingArray(name.to_string()))
+  }
+
+  /// Materialises the tensor called `name` on `dev`. Native devices on
+  /// little-endian hosts point straight into the mapped file when the
+  /// data is aligned for `T`, everything else gets a copy.
+  pub fn tensor<T: Element>(&self, name: &str, dev: &BufferDevice) -> Result<Tensor<T>, Error> {
+    let info = try!(self.info(name));
+    let dtype = try!(info.data_type());
+    if dtype != T::DATA_TYPE {
+      return Err(Error::DtypeMismatch {
+        expected: T::DATA_TYPE,
+        actual: dtype
+      })
+    }
+
+    let start = self.data_start + info.data_offsets.0;
+    let len = info.data_offsets.1 - info.data_offsets.0;
+    let bytes = &self.mmap[start..(start + len)];
+    if !T::validate(bytes) {
+      return Err(Error::InvalidData(info.dtype.clone()))
+    }
+
+    let mapped = match *dev {
+      #[cfg(feature = "native")]
+      BufferDevice::Native(ref ndev) => {
+        let mem = native::Memory::from_mapping(self.mmap.clone(), start, len);
+        if cfg!(target_endian = "little") && mem.is_aligned_for::<T>() {
+          Some(try!(RawBuffer::from_native_memory(ndev, mem)))
+        } else {
+          None
+        }
+      },
+
+      // Simulated devices keep their memory apart, the data is copied
+      #[cfg(feature = "sim")]
+      BufferDevice::Sim(_) => None
+    };
+
+    if let Some(raw) = mapped {
+      return Ok(try!(Tensor::new(raw.into(), info.shape.clone())))
+    }
+
+    // Every supported element type is valid when zeroed
+    let mut data: Vec<T> = vec![unsafe { mem::zeroed() }; len / mem::size_of::<T>()];
+    {
+      let data = as_mut_bytes(&mut data);
+      data.copy_from_slice(bytes);
+      if cfg!(target_endian = "big") {
+        for elem in data.chunks_mut(T::DATA_TYPE.scalar_size()) {
+          elem.reverse();
+        }
+      }
+    }
+
+    Ok(try!(Tensor::from_vec(dev.clone(), data, info.shape.clone())))
+  }
+
+  pub fn buffer<T: Element>(&self, name: &str, dev: &BufferDevice) -> Result<Buffer<T>, Error> {
+    self.tensor(name, dev).map(Tensor::into_buffer)
+  }
+
+  /// Materialises the tensor called `name` with whatever element type
+  /// the file stores it in, returning the buffer along with its shape.
+  pub fn any_buffer(&self, name: &str, dev: &BufferDevice) -> Result<(AnyBuffer, Vec<usize>), Error> {
+    let dtype = try!(try!(self.info(name)).data_type());
+    with_element_type!(dtype, any_tensor(self, name, dev))
+  }
+}
+
+fn any_tensor<T: Element>(file: &SafeTensors, name: &str, dev: &BufferDevice) -> Result<(AnyBuffer, Vec<usize>), Error> {
+  let tensor: Tensor<T> = try!(file.tensor(name, dev));
+  let shape = tensor.dims().to_vec();
+  Ok((tensor.into_buffer().into(), shape))
+}
+
+impl TensorInfo {
+  pub fn data_type(&self) -> Result<DataType, Error> {
+    DataType::from_safetensors_name(&self.dtype).
+      ok_or_else(|| Error::UnsupportedDtype(self.dtype.clone()))
+  }
+}
+
+fn parse_metadata(entry: Value) -> Result<HashMap<String, String>, Error> {
+  let entries = match entry {
+    Value::Object(entries) => entries,
+    _ => return Err(Error::InvalidHeader("__metadata__ is not an object".to_string()))
+  };
+
+  let mut metadata = HashMap::new();
+  for (key, value) in entries {
+    match value {
+      Value::String(value) => { metadata.insert(key, value); },
+      _ => return Err(Error::InvalidHeader(format!("metadata {:?} is not a string", key)))
+    }
+  }
+
+  Ok(metadata)
+}
+
+fn parse_info(name: &str, entry: &Value, data_len: usize) -> Result<TensorInfo, Error> {
+  let invalid = |what: &str| Error::InvalidHeader(format!("{:?} has no valid {}", name, what));
+
+  let dtype = try!(entry.get("dtype").and_then(Value::as_str).ok_or_else(|| invalid("dtype")));
+  let elem_size = match DTYPES.iter().find(|&&(d, _)| d == dtype) {
+    Some(&(_, size)) => size,
+    None => return Err(Error::UnsupportedDtype(dtype.to_string()))
+  };
+
+  let shape = try!(entry.get("shape").and_then(Value::as_array).ok_or_else(|| invalid("shape")));
+  let shape: Vec<usize> = try!(shape.iter().
+                               map(|dim| dim.as_u64().map(|dim| dim as usize)).
+                               collect::<Option<_>>().
+                               ok_or_else(|| invalid("shape")));
+
+  let offsets = try!(entry.get("data_offsets").and_then(Value::as_array).ok_or_else(|| invalid("data_offsets")));
+  let (start, end) = match (offsets.len(), offsets.get(0).and_then(Value::as_u64), offsets.get(1).and_then(Value::as_u64)) {
+    (2, Some(start), Some(end)) => (start as usize, end as usize),
+    _ => return Err(invalid("data_offsets"))
+  };
+  if start > end || end > data_len {
+    return Err(Error::InvalidOffsets {
+      name: name.to_string(),
+      start: start,
+      end: end
+    })
+  }
+
+  let expected = try!(shape.iter().
+                      try_fold(elem_size, |len, &dim| len.checked_mul(dim)).
+                      ok_or_else(|| invalid("shape")));
+  if end - start != expected {
+    return Err(Error::SizeMismatch {
+      expected: expected,
+      actual: end - start
+    })
+  }
+
+  Ok(TensorInfo {
+    dtype: dtype.to_string(),
+    shape: shape,
+    data_offsets: (start, end)
+  })
+}
+
+impl<'a> Default for SafeTensorsWriter<'a> {
+  fn default() -> SafeTensorsWriter<'a> { SafeTensorsWriter::new() }
+}
+
+impl<'a> SafeTensorsWriter<'a> {
+  pub fn new() -> SafeTensorsWriter<'a> {
+    SafeTensorsWriter {
+      entries: Vec::new(),
+      metadata: Map::new()
+    }
+  }
+
+  pub fn metadata(&mut self, key: &str, value: &str) {
+    self.metadata.insert(key.to_string(), Value::String(value.to_string()));
+  }
+
+  pub fn add<T: Element>(&mut self, name: &str, shape: &[usize], data: &'a [T]) -> Result<(), Error> {
+    if shape.iter().product::<usize>() != data.len() {
+      return Err(Error::Buffer(buffer::Error::InvalidShape {
+        shape: shape.to_vec(),
+        len: data.len()
+      }))
+    }
+
+    if name == "__metadata__" || self.entries.iter().any(|entry| entry.name == name) {
+      return Err(Error::InvalidHeader(format!("duplicate tensor name {:?}", name)))
+    }
+
+    let dtype = match T::DATA_TYPE.safetensors_name() {
+      Some(dtype) => dtype,
+      None => return Err(Error::UnsupportedDtype(T::DATA_TYPE.to_string()))
+    };
+
+    self.entries.push(Entry {
+      name: name.to_string(),
+      dtype: dtype,
+      shape: shape.to_vec(),
+      scalar_size: T::DATA_TYPE.scalar_size(),
+      data: as_bytes(data)
+    });
+    Ok(())
+  }
+
+  /// Adds `tensor`, whose copy on `dev` has to be up to date.
+  pub fn add_tensor<T: Element>(&mut self, name: &str, tensor: &'a Tensor<T>, dev: &native::Device) -> Result<(), Error> {
+    let data = try!(host_data(tensor, dev));
+    self.add(name, tensor.dims(), data)
+  }
+
+  pub fn write<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+    let mut header = Map::new();
+    if !self.metadata.is_empty() {
+      header.insert("__metadata__".to_string(), Value::Object(self.metadata.clone()));
+    }
+
+    let mut offset = 0;
+    for entry in &self.entries {
+      let mut info = Map::new();
+      info.insert("dtype".to_string(), Value::from(entry.dtype));
+      info.insert("shape".to_string(), Value::from(entry.shape.clone()));
+      info.insert("data_offsets".to_string(), Value::from(vec![offset, offset + entry.data.len()]));
+      header.insert(entry.name.clone(), Value::Object(info));
+      offset += entry.data.len();
+    }
+
+    let mut header = try!(serde_json::to_string(&Value::Object(header)).
+                          map_err(|err| Error::InvalidHeader(err.to_string())));
+    while header.len() % HEADER_ALIGN != 0 {
+      header.push(' ');
+    }
+
+    try!(w.write_all(&(header.len() as u64).to_le_bytes()));
+    try!(w.write_all(header.as_bytes()));
+    for entry in &self.entries {
+      if cfg!(target_endian = "big") {
+        let mut data = entry.data.to_vec();
+        for elem in data.chunks_mut(entry.scalar_size) {
+          elem.reverse();
+        }
+        try!(w.write_all(&data));
+      } else {
+        try!(w.write_all(entry.data));
+      }
+    }
+
+    Ok(())
+  }
+
+  pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+    let mut w = BufWriter::new(try!(File::create(path)));
+    try!(self.write(&mut w));
+    try!(w.flush());
+    Ok(())
+  }
+}
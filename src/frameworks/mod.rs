@@ -1 +1,3 @@
 pub mod native;
+#[cfg(feature = "sim")]
+pub mod sim;
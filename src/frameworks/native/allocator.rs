@@ -0,0 +1,312 @@
+use std::alloc::{self, Layout};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::mem;
+use std::ptr;
+
+use super::Error;
+use super::numa;
+use super::memory::{Memory, DEFAULT_ALIGN};
+
+// Smallest block handed out, tiny allocations share one size class
+const MIN_BLOCK_SIZE: usize = 256;
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryConfig {
+  // Most bytes kept in freed blocks before blocks go back to the system
+  pub max_cached_bytes: usize,
+
+  // Blocks above this size are never cached
+  pub max_cached_block: usize,
+
+  // Most bytes the device may hold at once, in use or cached
+  pub budget: Option<usize>
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+  // Allocations served from a cached block
+  pub hits: usize,
+
+  // Allocations that had to go to the system allocator
+  pub misses: usize,
+
+  // Bytes currently held in cached blocks
+  pub cached_bytes: usize,
+  pub cached_blocks: usize,
+
+  // Bytes currently handed out to live memory
+  pub in_use_bytes: usize,
+
+  pub budget: Option<usize>
+}
+
+/// Caching allocator behind a native device. Freed blocks are kept in
+/// buckets of power-of-two size classes and handed out again for
+/// allocations of the same class.
+#[derive(Clone)]
+pub struct Allocator {
+  inner: Arc<Inner>
+}
+
+struct Inner {
+  config: MemoryConfig,
+
+  // NUMA node fresh blocks are placed on
+  node: Option<usize>,
+  state: Mutex<State>
+}
+
+struct State {
+  free: HashMap<usize, Vec<Block>>,
+  stats: MemoryStats
+}
+
+struct Block(*mut u8);
+
+// Cached blocks are not referenced by anything but the allocator
+unsafe impl Send for Block { }
+
+impl Default for MemoryConfig {
+  fn default() -> MemoryConfig {
+    MemoryConfig {
+      max_cached_bytes: 256 * 1024 * 1024,
+      max_cached_block: 64 * 1024 * 1024,
+      budget: None
+    }
+  }
+}
+
+impl MemoryStats {
+  /// Bytes that can still be allocated before hitting the budget,
+  /// counting cached blocks as free.
+  pub fn available(&self) -> Option<usize> {
+    self.budget.map(|budget| budget.saturating_sub(self.in_use_bytes))
+  }
+}
+
+impl Allocator {
+  pub fn new(config: MemoryConfig, node: Option<usize>) -> Allocator {
+    Allocator {
+      inner: Arc::new(Inner {
+        config: config,
+        node: node,
+        state: Mutex::new(State {
+          free: HashMap::new(),
+          stats: MemoryStats {
+            budget: config.budget,
+            .. MemoryStats::default()
+          }
+        })
+      })
+    }
+  }
+
+  pub fn config(&self) -> &MemoryConfig { &self.inner.config }
+
+  /// Power of two block size `size` is rounded up to, `None` if there
+  /// is no such size.
+  pub fn size_class(size: usize) -> Option<usize> {
+    if size <= MIN_BLOCK_SIZE {
+      Some(MIN_BLOCK_SIZE)
+    } else {
+      size.checked_next_power_of_two()
+    }
+  }
+
+  /// Allocates zeroed memory, reusing a cached block when one of the
+  /// right size class is available. Fails if the allocation would go
+  /// over the device budget even after freeing every cached block.
+  pub fn alloc(&self, size: usize) -> Result<Memory, Error> {
+    if size == 0 {
+      return Ok(Memory::alloc(0))
+    }
+
+    let class = Self::size_class(size);
+    let cacheable = class.map_or(false, |class| class <= self.inner.config.max_cached_block);
+
+    // Blocks too large to cache are not rounded up to a size class
+    let block_size = match class {
+      Some(class) if cacheable => class,
+      _ => size
+    };
+
+    // Sizes this close to the end of the address space can never be
+    //   allocated
+    let layout = match Layout::from_size_align(block_size, DEFAULT_ALIGN) {
+      Ok(layout) => layout,
+      Err(_) => return Err(Error::OutOfMemory {
+        requested: size,
+        available: self.inner.state.lock().unwrap().stats.available().unwrap_or(0)
+      })
+    };
+
+    let (cached, trimmed) = {
+      let mut state = self.inner.state.lock().unwrap();
+      let block = if cacheable {
+        state.free.get_mut(&block_size).and_then(|blocks| blocks.pop())
+      } else { None };
+
+      let trimmed = match block {
+        Some(_) => {
+          state.stats.hits += 1;
+          state.stats.cached_bytes -= block_size;
+          state.stats.cached_blocks -= 1;
+          state.stats.in_use_bytes += block_size;
+          Vec::new()
+        },
+        None => {
+          state.stats.misses += 1;
+          try!(Self::reserve(&mut state, block_size))
+        }
+      };
+
+      (block, trimmed)
+    };
+
+    Self::free_blocks(trimmed);
+
+    let ptr = match cached {
+      Some(Block(ptr)) => {
+        // Reused blocks still hold whatever the last owner wrote
+        unsafe { ptr::write_bytes(ptr, 0, size) };
+        ptr
+      },
+      None => {
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+          let mut state = self.inner.state.lock().unwrap();
+          state.stats.in_use_bytes -= block_size;
+
+          // Same as the budget errors of `reserve`, without a budget the
+          //   system ran out and nothing is known to be left
+          return Err(Error::OutOfMemory {
+            requested: block_size,
+            available: state.stats.available().unwrap_or(0)
+          })
+        }
+
+        // Cached blocks stay where they were first placed
+        if let Some(node) = self.inner.node {
+          numa::prefer_node(ptr, block_size, node);
+        }
+
+        ptr
+      }
+    };
+
+    Ok(Memory::from_raw_parts(ptr, size, layout, Some(self.clone())))
+  }
+
+  /// Tracks the allocation of `vec` as device memory without copying it.
+  /// Fails if the device budget cannot hold it.
+  pub fn adopt<T: Sized + Copy>(&self, vec: Vec<T>) -> Result<Memory, Error> {
+    // Spare capacity stays part of the allocation
+    let size = vec.capacity() * mem::size_of::<T>();
+
+    let trimmed = try!(Self::reserve(&mut self.inner.state.lock().unwrap(), size));
+    Self::free_blocks(trimmed);
+
+    let mut memory = Memory::from_vec(vec);
+    memory.set_allocator(self.clone());
+    Ok(memory)
+  }
+
+  /// Stops tracking a block whose allocation was handed to someone else.
+  pub fn untrack(&self, layout: Layout) {
+    self.inner.state.lock().unwrap().stats.in_use_bytes -= layout.size();
+  }
+
+  /// Takes back a block allocated by `alloc`, caching it unless that
+  /// would go over the configured limits.
+  pub fn release(&self, ptr: *mut u8, layout: Layout) {
+    let class = layout.size();
+    let cacheable = class <= self.inner.config.max_cached_block &&
+      layout.align() == DEFAULT_ALIGN &&
+      Self::size_class(class) == Some(class);
+
+    {
+      let mut state = self.inner.state.lock().unwrap();
+      state.stats.in_use_bytes -= class;
+
+      if cacheable && state.stats.cached_bytes + class <= self.inner.config.max_cached_bytes {
+        state.free.entry(class).or_insert_with(Vec::new).push(Block(ptr));
+        state.stats.cached_bytes += class;
+        state.stats.cached_blocks += 1;
+        return
+      }
+    }
+
+    unsafe { alloc::dealloc(ptr, layout) }
+  }
+
+  /// Returns every cached block to the system, giving back the number of
+  /// bytes freed.
+  pub fn trim(&self) -> usize {
+    let free = Self::take_cached(&mut self.inner.state.lock().unwrap());
+    Self::free_blocks(free)
+  }
+
+  // Counts `size` more bytes as in use. Cached blocks count against the
+  // budget too, so they are given up before failing; the caller frees
+  // them once the state lock is released.
+  fn reserve(state: &mut State, size: usize) -> Result<Vec<(usize, Vec<Block>)>, Error> {
+    let used = state.stats.in_use_bytes + state.stats.cached_bytes;
+
+    let trimmed = match state.stats.budget {
+      Some(budget) if used + size > budget => {
+        let available = budget.saturating_sub(state.stats.in_use_bytes);
+        if size > available {
+          return Err(Error::OutOfMemory {
+            requested: size,
+            available: available
+          })
+        }
+
+        Self::take_cached(state)
+      },
+      _ => Vec::new()
+    };
+
+    state.stats.in_use_bytes += size;
+    Ok(trimmed)
+  }
+
+  fn take_cached(state: &mut State) -> Vec<(usize, Vec<Block>)> {
+    state.stats.cached_bytes = 0;
+    state.stats.cached_blocks = 0;
+    state.free.drain().collect()
+  }
+
+  fn free_blocks(free: Vec<(usize, Vec<Block>)>) -> usize {
+    let mut freed = 0;
+    for (class, blocks) in free {
+      let layout = Layout::from_size_align(class, DEFAULT_ALIGN).unwrap();
+
+      for Block(ptr) in blocks {
+        unsafe { alloc::dealloc(ptr, layout) };
+        freed += class;
+      }
+    }
+
+    freed
+  }
+
+  pub fn stats(&self) -> MemoryStats {
+    self.inner.state.lock().unwrap().stats
+  }
+}
+
+impl Drop for Inner {
+  fn drop(&mut self) {
+    let state = self.state.get_mut().unwrap();
+
+    for (&class, blocks) in state.free.iter() {
+      let layout = Layout::from_size_align(class, DEFAULT_ALIGN).unwrap();
+
+      for &Block(ptr) in blocks.iter() {
+        unsafe { alloc::dealloc(ptr, layout) };
+      }
+    }
+  }
+}
@@ -0,0 +1,171 @@
+use std::fmt;
+use std::sync::Arc;
+
+use futures_cpupool::Builder;
+
+use hardware::Hardware as Hware;
+use profile::Profiler;
+use super::{Device, Error, Hardware, MemoryConfig};
+use super::{numa, threads};
+
+// Nice values Linux accepts
+const MIN_PRIORITY: i32 = -20;
+const MAX_PRIORITY: i32 = 19;
+
+type Hook = Arc<Fn() + Send + Sync>;
+
+/// Configures the thread pool and memory of a native device. Options
+/// left unset follow the hardware: one thread per compute unit, pinned
+/// to the CPUs of its NUMA node if it has one.
+#[derive(Clone)]
+pub struct DeviceBuilder {
+  hardware: Hardware,
+  memory: MemoryConfig,
+  pool_size: Option<usize>,
+  stack_size: Option<usize>,
+  name_prefix: Option<String>,
+  affinity: Option<Vec<usize>>,
+  priority: Option<i32>,
+  after_start: Option<Hook>,
+  before_stop: Option<Hook>,
+  profiler: Option<Arc<Profiler>>
+}
+
+impl DeviceBuilder {
+  pub fn new(hardware: &Hardware) -> DeviceBuilder {
+    DeviceBuilder {
+      hardware: hardware.clone(),
+      memory: MemoryConfig::default(),
+      pool_size: None,
+      stack_size: None,
+      name_prefix: None,
+      affinity: None,
+      priority: None,
+      after_start: None,
+      before_stop: None,
+      profiler: None
+    }
+  }
+
+  pub fn memory(mut self, config: MemoryConfig) -> DeviceBuilder {
+    self.memory = config;
+    self
+  }
+
+  /// Number of threads in the pool.
+  pub fn pool_size(mut self, size: usize) -> DeviceBuilder {
+    self.pool_size = Some(size);
+    self
+  }
+
+  /// Stack size of each pool thread in bytes.
+  pub fn stack_size(mut self, size: usize) -> DeviceBuilder {
+    self.stack_size = Some(size);
+    self
+  }
+
+  /// Prefix of the pool thread names, which are numbered after it.
+  pub fn name_prefix(mut self, prefix: &str) -> DeviceBuilder {
+    self.name_prefix = Some(prefix.to_string());
+    self
+  }
+
+  /// Pins the pool threads to `cpus` instead of the CPUs of the hardware.
+  /// An empty list lets them run anywhere.
+  pub fn affinity(mut self, cpus: Vec<usize>) -> DeviceBuilder {
+    self.affinity = Some(cpus);
+    self
+  }
+
+  /// Nice value of the pool threads, from -20 for the highest priority
+  /// to 19 for the lowest.
+  pub fn priority(mut self, nice: i32) -> DeviceBuilder {
+    self.priority = Some(nice);
+    self
+  }
+
+  /// Runs `f` on every pool thread once it is set up, before it takes
+  /// any work.
+  pub fn after_start<F: Fn() + Send + Sync + 'static>(mut self, f: F) -> DeviceBuilder {
+    self.after_start = Some(Arc::new(f));
+    self
+  }
+
+  /// Runs `f` on every pool thread right before it exits.
+  pub fn before_stop<F: Fn() + Send + Sync + 'static>(mut self, f: F) -> DeviceBuilder {
+    self.before_stop = Some(Arc::new(f));
+    self
+  }
+
+  /// Profiler the device sends its spans to from the start.
+  pub fn profiler(mut self, profiler: Arc<Profiler>) -> DeviceBuilder {
+    self.profiler = Some(profiler);
+    self
+  }
+
+  pub fn build(self) -> Result<Device, Error> {
+    let pool_size = self.pool_size.unwrap_or(self.hardware.compute_units());
+    if pool_size == 0 {
+      return Err(Error::InvalidOption("pool size has to be positive"))
+    }
+
+    if let Some(nice) = self.priority {
+      if nice < MIN_PRIORITY || nice > MAX_PRIORITY {
+        return Err(Error::InvalidOption("priority has to be between -20 and 19"))
+      }
+    }
+
+    let mut builder = Builder::new();
+    builder.pool_size(pool_size).
+      name_prefix(self.name_prefix.unwrap_or(format!("{}-", self.hardware.name())));
+    if let Some(size) = self.stack_size {
+      builder.stack_size(size);
+    }
+
+    let cpus = self.affinity.unwrap_or(self.hardware.cpus().to_vec());
+    let node = self.hardware.node();
+    let priority = self.priority;
+    let after_start = self.after_start;
+    builder.after_start(move || {
+      if !cpus.is_empty() {
+        threads::pin_thread(&cpus);
+      }
+
+      // Threads of a NUMA node allocate close to it
+      if let Some(node) = node {
+        numa::prefer_node_for_thread(node);
+      }
+
+      if let Some(nice) = priority {
+        threads::set_priority(nice);
+      }
+
+      if let Some(ref f) = after_start {
+        f();
+      }
+    });
+
+    if let Some(f) = self.before_stop {
+      builder.before_stop(move || f());
+    }
+
+    let device = Device::with_memory(self.hardware, builder, self.memory);
+    device.set_profiler(self.profiler);
+    Ok(device)
+  }
+}
+
+impl fmt::Debug for DeviceBuilder {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("DeviceBuilder").
+      field("hardware", &self.hardware).
+      field("memory", &self.memory).
+      field("pool_size", &self.pool_size).
+      field("stack_size", &self.stack_size).
+      field("name_prefix", &self.name_prefix).
+      field("affinity", &self.affinity).
+      field("priority", &self.priority).
+      field("profiler", &self.profiler.is_some()).
+      finish()
+  }
+}
@@ -1,14 +1,30 @@
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicIsize, Ordering};
 
-use futures::Future;
-use futures_cpupool::{CpuPool, Builder};
+use futures::{Future, IntoFuture};
+use futures_cpupool::{CpuPool, CpuFuture, Builder};
 
+use cancel::CancelToken;
 use device;
+use hardware::Hardware as Hware;
+use profile::{Profiler, Timer};
+use stream::Stream;
 use super::Hardware;
 use super::Memory;
 use super::Error;
+use super::allocator::{Allocator, MemoryConfig, MemoryStats};
 use std::hash::{Hash, Hasher};
 use std::fmt;
+use std::mem;
+use std::ops::Range;
+
+// Every native device gets its own id for the lifetime of the process,
+// so buffers keep a separate copy per device
+static NEXT_DEVICE_ID: AtomicIsize = AtomicIsize::new(0);
+
+/// Memories of a copy, destination first, handed back whether it
+/// succeeds or not.
+pub type Transfer = Box<Future<Item=(Memory, Memory),Error=(Error, Memory, Memory)>>;
 
 #[derive(Debug, Clone)]
 pub struct Device {
@@ -18,18 +34,27 @@ pub struct Device {
 
 struct Inner {
   hardware: Hardware,
-  pool: CpuPool
+  pool: CpuPool,
+  allocator: Allocator,
+  profiler: RwLock<Option<Arc<Profiler>>>
 }
 
 impl Device {
-  pub fn new(hardware: Hardware, mut builder: Builder) -> Device {
+  pub fn new(hardware: Hardware, builder: Builder) -> Device {
+    Self::with_memory(hardware, builder, MemoryConfig::default())
+  }
+
+  pub fn with_memory(hardware: Hardware, mut builder: Builder, config: MemoryConfig) -> Device {
+    let allocator = Allocator::new(config, hardware.node());
     let inner = Arc::new(Inner {
       hardware: hardware,
-      pool: builder.create()
+      pool: builder.create(),
+      allocator: allocator,
+      profiler: RwLock::new(None)
     });
 
     Device {
-      id: 0,
+      id: NEXT_DEVICE_ID.fetch_add(1, Ordering::SeqCst),
       inner: inner
     }
   }
@@ -37,6 +62,119 @@ impl Device {
   pub fn pool(&self) -> &CpuPool {
     &self.inner.pool
   }
+
+  pub fn memory_config(&self) -> &MemoryConfig {
+    self.inner.allocator.config()
+  }
+
+  pub fn memory_stats(&self) -> MemoryStats {
+    self.inner.allocator.stats()
+  }
+
+  /// Frees all memory blocks cached by the device allocator and returns
+  /// the number of bytes given back to the system.
+  pub fn trim(&self) -> usize {
+    self.inner.allocator.trim()
+  }
+
+  /// Sends a span for every operation from now on to `profiler`, or
+  /// stops profiling if it is none.
+  pub fn set_profiler(&self, profiler: Option<Arc<Profiler>>) {
+    *self.inner.profiler.write().unwrap() = profiler;
+  }
+
+  pub fn profiler(&self) -> Option<Arc<Profiler>> {
+    self.inner.profiler.read().unwrap().clone()
+  }
+
+  /// Times an operation moving `bytes` on the device, for kernels that
+  /// run on its pool.
+  pub fn timer(&self, name: &'static str, bytes: usize) -> Timer {
+    Timer::new(self.profiler(), name, "native", self.id, bytes)
+  }
+
+  // Runs `f` on the pool inside a span
+  fn spawn_timed<F, R, E>(&self, name: &'static str, bytes: usize, f: F) -> CpuFuture<R, E>
+    where F: FnOnce() -> Result<R, E> + Send + 'static, R: Send + 'static, E: Send + 'static {
+    let mut timer = self.timer(name, bytes);
+    self.inner.pool.spawn_fn(move || {
+      timer.start();
+      let res = f();
+      timer.finish();
+      res
+    })
+  }
+
+  /// Copies the contents of `src` into `dst` on the device pool. Both
+  /// memories are handed back once the copy is done, or with the error
+  /// if it fails.
+  pub fn sync_from_memory(&self,
+                          dst: Memory,
+                          src: Memory) -> Transfer {
+    self.sync_from_memory_with(dst, src, &CancelToken::new())
+  }
+
+  /// Like `sync_from_memory`, but checks `token` between chunks of the
+  /// copy and fails with `Error::Cancelled` once it is set.
+  pub fn sync_from_memory_with(&self,
+                               dst: Memory,
+                               src: Memory,
+                               token: &CancelToken) -> Transfer {
+    // Read-only mappings cannot take the copy, it goes to fresh memory
+    let dst = if dst.is_read_only() {
+      match self.inner.allocator.alloc(dst.len()) {
+        Ok(copy) => copy,
+        Err(err) => return Box::new(Err((err, dst, src)).into_future())
+      }
+    } else { dst };
+
+    let token = token.clone();
+    Box::new(self.spawn_timed("sync_from_memory", src.len(), move || {
+      let mut dst = dst;
+      match dst.copy_from_memory_with(&src, &token) {
+        Ok(()) => Ok((dst, src)),
+        Err(err) => Err((err, dst, src))
+      }
+    }))
+  }
+
+  /// Like `sync_from_memory`, but only copies the given byte ranges.
+  pub fn sync_ranges_from_memory(&self,
+                                 dst: Memory,
+                                 src: Memory,
+                                 ranges: Vec<Range<usize>>) -> Transfer {
+    self.sync_ranges_from_memory_with(dst, src, ranges, &CancelToken::new())
+  }
+
+  /// Like `sync_ranges_from_memory`, but checks `token` between chunks
+  /// of the copy.
+  pub fn sync_ranges_from_memory_with(&self,
+                                      dst: Memory,
+                                      src: Memory,
+                                      ranges: Vec<Range<usize>>,
+                                      token: &CancelToken) -> Transfer {
+    let allocator = self.inner.allocator.clone();
+    let bytes = ranges.iter().map(|r| r.len()).sum();
+    let token = token.clone();
+    Box::new(self.spawn_timed("sync_ranges_from_memory", bytes, move || {
+      // Everything outside the ranges has to be kept when a read-only
+      // mapping moves to fresh memory
+      let mut dst = if dst.is_read_only() {
+        let copy = allocator.alloc(dst.len()).and_then(|mut copy| {
+          copy.copy_from_memory(&dst).map(|_| copy)
+        });
+        match copy {
+          Ok(copy) => copy,
+          Err(err) => return Err((err, dst, src))
+        }
+      } else { dst };
+
+      match dst.copy_ranges_from_memory_with(&src, &ranges, &token) {
+        Ok(()) => Ok((dst, src)),
+        Err(err) => Err((err, dst, src))
+      }
+    }))
+  }
 }
 
 impl device::Device for Device {
@@ -47,25 +185,84 @@ impl device::Device for Device {
   fn id(&self) -> isize { self.id }
   fn hardware(&self) -> &Self::H { &self.inner.hardware }
   fn alloc_memory(&self, size: usize) -> Result<Self::M, Self::Error> {
-    Ok(Memory::alloc(size))
+    let mut timer = self.timer("alloc_memory", size);
+    timer.start();
+    let res = self.inner.allocator.alloc(size);
+    timer.finish();
+    res
+  }
+
+  fn memory_from_vec<T: Send + Copy + Sized + 'static>(&self,
+                                                       vec: Vec<T>) -> Result<Self::M, Self::Error> {
+    self.inner.allocator.adopt(vec)
   }
 
+  // Host memory is device memory here, so the vector replaces the old
+  // memory instead of being copied into it
   fn sync_from_vec<T: Send + Copy + Sized + 'static>(&self,
-                                                     mut mem: Self::M,
+                                                     mem: Self::M,
                                                      vec: Vec<T>) -> Box<Future<Item=Self::M,Error=Self::Error>> {
-    Box::new(self.inner.pool.spawn_fn(move || {
-      try!(mem.copy_from(&vec));
-      Ok(mem)
-    }))
+    let actual = vec.len() * mem::size_of::<T>();
+    if mem.len() != actual {
+      return Box::new(Err(Error::SizeMismatch {
+        expected: mem.len(),
+        actual: actual
+      }).into_future())
+    }
+
+    drop(mem);
+    let mut timer = self.timer("sync_from_vec", actual);
+    timer.start();
+    let res = self.memory_from_vec(vec);
+    timer.finish();
+    Box::new(res.into_future())
   }
 
   fn sync_to_vec<T: Send + Copy + Sized + 'static>(&self,
                                                    mem: Self::M) -> Box<Future<Item=(Self::M, Vec<T>),Error=Self::Error>> {
-    Box::new(self.inner.pool.spawn_fn(move || {
-      let vec: Vec<T> = try!(mem.clone().into_vec());
+    Box::new(self.spawn_timed("sync_to_vec", mem.len(), move || {
+      let vec: Vec<T> = try!(mem.to_vec());
+      Ok((mem, vec))
+    }))
+  }
+
+  fn write_range<T: Send + Copy + Sized + 'static>(&self,
+                                                   mut mem: Self::M,
+                                                   offset: usize,
+                                                   vec: Vec<T>) -> Box<Future<Item=Self::M,Error=Self::Error>> {
+    let bytes = vec.len() * mem::size_of::<T>();
+    Box::new(self.spawn_timed("write_range", bytes, move || {
+      try!(mem.copy_from_at(offset * mem::size_of::<T>(), &vec));
+      Ok(mem)
+    }))
+  }
+
+  fn read_range<T: Send + Copy + Sized + 'static>(&self,
+                                                  mem: Self::M,
+                                                  range: Range<usize>) -> Box<Future<Item=(Self::M, Vec<T>),Error=Self::Error>> {
+    let size = mem::size_of::<T>();
+    Box::new(self.spawn_timed("read_range", range.len() * size, move || {
+      let vec: Vec<T> = try!(mem.range_to_vec((range.start * size)..(range.end * size)));
       Ok((mem, vec))
     }))
   }
+
+  /// Hands the allocation over without copying only when it is aligned
+  /// exactly like `T`, which in practice means memory that came from a
+  /// `Vec<T>`. Memory from `alloc_memory` is aligned to `DEFAULT_ALIGN`
+  /// and always copied, on the device threads.
+  fn into_vec<T: Send + Copy + Sized + 'static>(&self,
+                                                mem: Self::M) -> Box<Future<Item=Vec<T>,Error=Self::Error>> {
+    if mem.can_reuse_as::<T>() {
+      Box::new(mem.into_vec().into_future())
+    } else {
+      Box::new(self.spawn_timed("into_vec", mem.len(), move || mem.into_vec()))
+    }
+  }
+
+  fn new_stream(&self) -> Stream {
+    Stream::new(&format!("{}-stream-", self.inner.hardware.name()))
+  }
 }
 
 impl PartialEq for Device {
@@ -1,4 +1,58 @@
-#[derive(Debug, Clone, Copy)]
+use std::error;
+use std::fmt;
+
+use cancel::Cancelled;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Error {
-  OutOfMemory
+  // Allocation would go over the device budget or the system is out
+  // of memory
+  OutOfMemory { requested: usize, available: usize },
+
+  // Source and destination of a copy differ in size, in bytes
+  SizeMismatch { expected: usize, actual: usize },
+
+  // Memory length in bytes is not a multiple of the element size
+  InvalidLength { len: usize, elem_size: usize },
+
+  // Memory does not start at a multiple of the element alignment
+  Misaligned { address: usize, align: usize },
+
+  // Byte range reaches past the end of the memory
+  OutOfRange { start: usize, end: usize, len: usize },
+
+  // Memory is a read-only file mapping
+  ReadOnly,
+
+  // Device builder option is out of range
+  InvalidOption(&'static str),
+
+  // Copy stopped because its cancel token was set
+  Cancelled
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Error::OutOfMemory { requested, available } =>
+        write!(f, "out of memory: requested {} bytes, {} bytes available", requested, available),
+      Error::SizeMismatch { expected, actual } =>
+        write!(f, "size mismatch: expected {} bytes, got {} bytes", expected, actual),
+      Error::InvalidLength { len, elem_size } =>
+        write!(f, "invalid length: {} bytes is not a multiple of the element size {}", len, elem_size),
+      Error::Misaligned { address, align } =>
+        write!(f, "misaligned memory: address {:#x} is not aligned to {} bytes", address, align),
+      Error::OutOfRange { start, end, len } =>
+        write!(f, "out of range: bytes {}..{} of memory with {} bytes", start, end, len),
+      Error::ReadOnly => write!(f, "memory is read-only"),
+      Error::InvalidOption(option) => write!(f, "invalid device option: {}", option),
+      Error::Cancelled => write!(f, "copy was cancelled")
+    }
+  }
+}
+
+impl error::Error for Error { }
+
+impl From<Cancelled> for Error {
+  fn from(_: Cancelled) -> Error { Error::Cancelled }
 }
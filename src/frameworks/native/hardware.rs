@@ -1,22 +1,247 @@
-use hardware;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use std::thread;
+
+use hardware::{self, Cache, CacheType, Simd};
 
 #[derive(Debug, Clone)]
 pub struct Hardware {
   name: String,
-  compute_units: usize
+  model_name: Option<String>,
+  compute_units: usize,
+  physical_units: usize,
+  caches: Vec<Cache>,
+  simd: Vec<Simd>,
+
+  // NUMA node and CPUs the hardware is limited to, none for the whole
+  // machine
+  node: Option<usize>,
+  cpus: Vec<usize>
 }
 
+// Flags in /proc/cpuinfo for each instruction set. x86 lists them under
+// `flags`, ARM under `Features`.
+const SIMD_FLAGS: &'static [(&'static str, Simd)] = &[
+  ("sse", Simd::SSE), ("sse2", Simd::SSE2), ("pni", Simd::SSE3),
+  ("ssse3", Simd::SSSE3), ("sse4_1", Simd::SSE41), ("sse4_2", Simd::SSE42),
+  ("avx", Simd::AVX), ("avx2", Simd::AVX2), ("fma", Simd::FMA),
+  ("avx512f", Simd::AVX512F), ("neon", Simd::NEON), ("asimd", Simd::NEON)
+];
+
 impl Hardware {
+  /// Detects the CPU of this machine.
   pub fn new() -> Hardware {
+    Hardware::detect(Path::new("/sys"))
+  }
+
+  // Detects the whole machine, reading sysfs below `sysfs`
+  pub(crate) fn detect(sysfs: &Path) -> Hardware {
+    let mut cpuinfo = String::new();
+    let mut hardware = match File::open("/proc/cpuinfo").and_then(|mut f| f.read_to_string(&mut cpuinfo)) {
+      Ok(_) => Hardware::from_cpuinfo(&cpuinfo),
+      Err(_) => Hardware::from_cpuinfo("")
+    };
+
+    if hardware.simd.is_empty() {
+      hardware.simd = detect_simd();
+    }
+    hardware.caches = read_caches(&sysfs.join("devices/system/cpu/cpu0/cache"));
+    hardware
+  }
+
+  // Narrows `machine` down to the CPUs of one NUMA node
+  pub(crate) fn for_node(machine: &Hardware, sysfs: &Path, node: usize, cpus: Vec<usize>) -> Hardware {
+    let cpu_dir = sysfs.join("devices/system/cpu");
+    let topology = |cpu: usize, name: &str| {
+      fs::read_to_string(cpu_dir.join(format!("cpu{}/topology/{}", cpu, name))).ok().
+        map(|value| value.trim().to_string())
+    };
+
+    let cores: Option<HashSet<(String, String)>> = cpus.iter().
+      map(|&cpu| match (topology(cpu, "physical_package_id"), topology(cpu, "core_id")) {
+        (Some(package), Some(core)) => Some((package, core)),
+        _ => None
+      }).
+      collect();
+
+    let mut caches = read_caches(&cpu_dir.join(format!("cpu{}/cache", cpus[0])));
+    if caches.is_empty() {
+      caches = machine.caches.clone();
+    }
+
+    Hardware {
+      name: format!("node{}", node),
+      model_name: machine.model_name.clone(),
+      compute_units: cpus.len(),
+      physical_units: cores.map(|cores| cores.len()).unwrap_or(cpus.len()),
+      caches: caches,
+      simd: machine.simd.clone(),
+      node: Some(node),
+      cpus: cpus
+    }
+  }
+
+  /// Describes the CPU listed in the contents of `/proc/cpuinfo`. Core
+  /// counts fall back to what the OS reports when the listing is empty.
+  pub fn from_cpuinfo(cpuinfo: &str) -> Hardware {
+    let mut model_name = None;
+    let mut logical = 0;
+    let mut cores = HashSet::new();
+    let mut simd = Vec::new();
+
+    // Processors are separated by blank lines
+    for processor in cpuinfo.split("\n\n") {
+      let mut physical_id = None;
+      let mut core_id = None;
+      let mut is_processor = false;
+
+      for line in processor.lines() {
+        let mut parts = line.splitn(2, ':');
+        let key = parts.next().unwrap_or("").trim();
+        let value = parts.next().unwrap_or("").trim();
+
+        match key {
+          "processor" => is_processor = true,
+          "physical id" => physical_id = Some(value.to_string()),
+          "core id" => core_id = Some(value.to_string()),
+          "model name" | "Model" if model_name.is_none() => model_name = Some(value.to_string()),
+          "flags" | "Features" if simd.is_empty() => {
+            let flags: HashSet<&str> = value.split_whitespace().collect();
+            simd = SIMD_FLAGS.iter().
+              filter(|&&(flag, _)| flags.contains(flag)).
+              map(|&(_, simd)| simd).
+              collect();
+            simd.dedup();
+          },
+          _ => ()
+        }
+      }
+
+      if is_processor {
+        logical += 1;
+        if let (Some(physical_id), Some(core_id)) = (physical_id, core_id) {
+          cores.insert((physical_id, core_id));
+        }
+      }
+    }
+
+    if logical == 0 {
+      logical = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    }
+
+    // Without topology, e.g. on ARM, every processor is its own core
+    let physical = if cores.is_empty() { logical } else { cores.len() };
+
     Hardware {
       name: "cpu".to_string(),
-      compute_units: 1
+      model_name: model_name,
+      compute_units: logical,
+      physical_units: physical,
+      caches: Vec::new(),
+      simd: simd,
+      node: None,
+      cpus: Vec::new()
+    }
+  }
+
+  /// NUMA node the hardware belongs to, none if it spans the machine.
+  pub fn node(&self) -> Option<usize> { self.node }
+
+  /// CPUs the hardware is limited to, empty if it may use every CPU.
+  pub fn cpus(&self) -> &[usize] { &self.cpus }
+}
+
+impl Default for Hardware {
+  fn default() -> Hardware { Hardware::new() }
+}
+
+// Queries CPUID for the instruction sets when /proc/cpuinfo lists none
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn detect_simd() -> Vec<Simd> {
+  let mut simd = Vec::new();
+  macro_rules! detect {
+    ($($feature:tt => $simd:ident),*) => {
+      $(if is_x86_feature_detected!($feature) { simd.push(Simd::$simd); })*
     }
   }
+
+  detect!("sse" => SSE, "sse2" => SSE2, "sse3" => SSE3, "ssse3" => SSSE3,
+          "sse4.1" => SSE41, "sse4.2" => SSE42, "avx" => AVX, "avx2" => AVX2,
+          "fma" => FMA, "avx512f" => AVX512F);
+  simd
+}
+
+// NEON is part of every AArch64 CPU
+#[cfg(target_arch = "aarch64")]
+fn detect_simd() -> Vec<Simd> { vec![Simd::NEON] }
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+fn detect_simd() -> Vec<Simd> { Vec::new() }
+
+// Reads the caches of one CPU from its sysfs `cache` directory
+fn read_caches(dir: &Path) -> Vec<Cache> {
+  let entries = match fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(_) => return Vec::new()
+  };
+
+  let mut caches: Vec<Cache> = entries.
+    filter_map(|entry| entry.ok()).
+    filter(|entry| entry.file_name().to_string_lossy().starts_with("index")).
+    filter_map(|entry| read_cache(&entry.path())).
+    collect();
+  caches.sort_by_key(|cache| (cache.level, cache.cache_type as u8));
+  caches
+}
+
+fn read_cache(dir: &Path) -> Option<Cache> {
+  let read = |name: &str| {
+    fs::read_to_string(dir.join(name)).ok().map(|value| value.trim().to_string())
+  };
+
+  let level = match read("level").and_then(|level| level.parse().ok()) {
+    Some(level) => level,
+    None => return None
+  };
+  let cache_type = match read("type").as_ref().map(|t| t.as_str()) {
+    Some("Data") => CacheType::Data,
+    Some("Instruction") => CacheType::Instruction,
+    Some("Unified") => CacheType::Unified,
+    _ => return None
+  };
+  let size = match read("size").and_then(|size| parse_size(&size)) {
+    Some(size) => size,
+    None => return None
+  };
+
+  Some(Cache {
+    level: level,
+    cache_type: cache_type,
+    size: size,
+    line_size: read("coherency_line_size").and_then(|size| size.parse().ok())
+  })
+}
+
+// Sizes are written like `32K` or `8M`
+fn parse_size(size: &str) -> Option<usize> {
+  let (digits, unit) = match size.chars().last() {
+    Some('K') => (&size[..size.len() - 1], 1 << 10),
+    Some('M') => (&size[..size.len() - 1], 1 << 20),
+    Some('G') => (&size[..size.len() - 1], 1 << 30),
+    _ => (size, 1)
+  };
+
+  digits.parse::<usize>().ok().map(|n| n * unit)
 }
 
 impl hardware::Hardware for Hardware {
   fn name(&self) -> &str { &self.name }
   fn hardware_type(&self) -> hardware::HardwareType { hardware::HardwareType::CPU }
   fn compute_units(&self) -> usize { self.compute_units }
+  fn physical_units(&self) -> usize { self.physical_units }
+  fn model_name(&self) -> Option<&str> { self.model_name.as_ref().map(|name| name.as_str()) }
+  fn caches(&self) -> &[Cache] { &self.caches }
+  fn simd(&self) -> &[Simd] { &self.simd }
 }
@@ -1,91 +1,437 @@
+use std::alloc::{self, Layout};
+use std::cmp;
 use std::result::Result;
+use std::fmt;
+use std::fs::File;
+use std::io;
 use std::mem;
+use std::ops::Range;
 use std::ptr;
 use std::slice;
+use std::sync::Arc;
 
+use memmap2::{Mmap, MmapMut, MmapOptions};
+
+use cancel::{CancelToken, COPY_CHUNK};
 use super::Error;
+use super::allocator::Allocator;
 use memory;
 
-#[derive(Debug, Clone)]
+// Default alignment of native memory, enough for any primitive type
+// and for SIMD registers up to 512 bits
+pub const DEFAULT_ALIGN: usize = 64;
+
+/// How a file is mapped into memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapMode {
+  // Pages are shared with the page cache and cannot be written
+  ReadOnly,
+
+  // Writes go to private copies of the touched pages and never reach
+  // the file
+  CopyOnWrite
+}
+
 pub struct Memory {
-  buf: Box<[u8]>
+  ptr: *mut u8,
+  len: usize,
+  layout: Layout,
+
+  // Allocator the block goes back to when dropped
+  allocator: Option<Allocator>,
+
+  // File mapping the memory points into, if any
+  mapping: Option<Mapping>
 }
 
+// Only held to keep the pages mapped for as long as the memory lives
+enum Mapping {
+  // Read-only mappings can be shared by several memories
+  Shared(Arc<Mmap>),
+  Private(#[allow(dead_code)] MmapMut)
+}
+
+// Memory owns its allocation exclusively, just like a Box<[u8]>
+unsafe impl Send for Memory { }
+unsafe impl Sync for Memory { }
+
 impl Memory {
   pub fn alloc(size: usize) -> Memory {
-    let vec: Vec<u8> = vec![0; size];
-    let buf = vec.into_boxed_slice();
+    Self::alloc_aligned(size, DEFAULT_ALIGN)
+  }
+
+  /// Allocates zeroed memory starting at a multiple of `align`.
+  ///
+  /// Panics if `align` is not a power of two.
+  pub fn alloc_aligned(size: usize, align: usize) -> Memory {
+    let layout = Layout::from_size_align(size, align).
+      expect("memory alignment must be a power of two");
+
+    let ptr = if size == 0 {
+      // Nothing to allocate, but the pointer still has to be aligned
+      align as *mut u8
+    } else {
+      let ptr = unsafe { alloc::alloc_zeroed(layout) };
+      if ptr.is_null() {
+        alloc::handle_alloc_error(layout)
+      }
+      ptr
+    };
+
+    Self::from_raw_parts(ptr, size, layout, None)
+  }
+
+  /// Takes over the allocation of `vec` without copying, spare capacity
+  /// included. The memory is aligned for `T` only, not to
+  /// `DEFAULT_ALIGN`.
+  pub fn from_vec<T: Sized + Copy>(vec: Vec<T>) -> Memory {
+    let mut vec = mem::ManuallyDrop::new(vec);
+    let len = vec.len() * mem::size_of::<T>();
+
+    // The layout covers the whole capacity, shrinking to the length
+    //   first would reallocate
+    let layout = Layout::array::<T>(vec.capacity()).unwrap();
+    let ptr = vec.as_mut_ptr() as *mut u8;
+
+    Self::from_raw_parts(ptr, len, layout, None)
+  }
+
+  /// Maps `len` bytes of `file` starting at byte `offset`. Pages are
+  /// only read from the file once they are touched.
+  pub fn map_file(file: &File, offset: u64, len: usize, mode: MapMode) -> io::Result<Memory> {
+    let mut options = MmapOptions::new();
+    options.offset(offset).len(len);
+
+    match mode {
+      MapMode::ReadOnly => {
+        let mmap = unsafe { try!(options.map(file)) };
+        Ok(Self::from_mapping(Arc::new(mmap), 0, len))
+      },
+      MapMode::CopyOnWrite => {
+        let mut mmap = unsafe { try!(options.map_copy(file)) };
+        let ptr = mmap.as_mut_ptr();
+        Ok(Self::mapped(ptr, len, Mapping::Private(mmap)))
+      }
+    }
+  }
+
+  /// Read-only memory pointing at `len` bytes of a shared mapping
+  /// starting at byte `offset`, without copying anything.
+  ///
+  /// Panics if the range reaches past the end of the mapping.
+  pub(crate) fn from_mapping(mmap: Arc<Mmap>, offset: usize, len: usize) -> Memory {
+    assert!(offset + len <= mmap.len(), "range reaches past the end of the mapping");
+
+    let ptr = unsafe { mmap.as_ptr().offset(offset as isize) as *mut u8 };
+    Self::mapped(ptr, len, Mapping::Shared(mmap))
+  }
+
+  fn mapped(ptr: *mut u8, len: usize, mapping: Mapping) -> Memory {
+    // Mappings start on a page boundary, but the offset into them might
+    // not keep that alignment
+    let align = 1 << (ptr as usize).trailing_zeros().min(DEFAULT_ALIGN.trailing_zeros());
+    let layout = Layout::from_size_align(len, align).unwrap();
 
+    let mut memory = Self::from_raw_parts(ptr, len, layout, None);
+    memory.mapping = Some(mapping);
+    memory
+  }
+
+  pub(crate) fn from_raw_parts(ptr: *mut u8,
+                               len: usize,
+                               layout: Layout,
+                               allocator: Option<Allocator>) -> Memory {
     Memory {
-      buf: buf
+      ptr: ptr,
+      len: len,
+      layout: layout,
+      allocator: allocator,
+      mapping: None
     }
   }
 
-  pub fn len(&self) -> usize { self.buf.len() }
+  pub fn len(&self) -> usize { self.len }
+
+  pub fn is_empty(&self) -> bool { self.len == 0 }
+
+  pub fn align(&self) -> usize { self.layout.align() }
+
+  pub fn is_mapped(&self) -> bool { self.mapping.is_some() }
+
+  pub fn is_read_only(&self) -> bool {
+    match self.mapping {
+      Some(Mapping::Shared(_)) => true,
+      _ => false
+    }
+  }
 
   pub fn as_ptr(&self) -> *const u8 {
-    self.buf.as_ptr()
+    self.ptr
   }
 
+  /// Panics if the memory is read-only.
   pub fn as_mut_ptr(&mut self) -> *mut u8 {
-    self.buf.as_mut_ptr()
+    assert!(!self.is_read_only(), "cannot write to read-only memory");
+    self.ptr
   }
 
-  pub fn try_as_slice<T: Sized + Copy>(&self) -> Result<&[T], Error> {
-    if self.len() % mem::size_of::<T>() != 0 {
-      // TODO: better error
-      return Err(Error::OutOfMemory)
+  pub fn as_bytes(&self) -> &[u8] {
+    unsafe { slice::from_raw_parts(self.ptr, self.len) }
+  }
+
+  /// Panics if the memory is read-only.
+  pub fn as_mut_bytes(&mut self) -> &mut [u8] {
+    assert!(!self.is_read_only(), "cannot write to read-only memory");
+    unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+  }
+
+  pub fn is_aligned_for<T: Sized>(&self) -> bool {
+    (self.ptr as usize) % mem::align_of::<T>() == 0
+  }
+
+  fn check_len<T: Sized>(&self) -> Result<usize, Error> {
+    let size = mem::size_of::<T>();
+
+    if size == 0 || self.len() % size != 0 {
+      return Err(Error::InvalidLength {
+        len: self.len(),
+        elem_size: size
+      })
+    }
+
+    Ok(self.len() / size)
+  }
+
+  fn check_writable(&self) -> Result<(), Error> {
+    if self.is_read_only() {
+      return Err(Error::ReadOnly)
     }
 
+    Ok(())
+  }
+
+  fn check_typed<T: Sized>(&self) -> Result<usize, Error> {
+    let len = try!(self.check_len::<T>());
+
+    if !self.is_aligned_for::<T>() {
+      return Err(Error::Misaligned {
+        address: self.ptr as usize,
+        align: mem::align_of::<T>()
+      })
+    }
+
+    Ok(len)
+  }
+
+  pub fn try_as_slice<T: Sized + Copy>(&self) -> Result<&[T], Error> {
+    let len = try!(self.check_typed::<T>());
+
     unsafe {
-      let p = self.as_ptr();
-      let pt = mem::transmute::<*const u8, *const T>(p);
-      Ok(slice::from_raw_parts(pt, self.len() / mem::size_of::<T>()))
+      Ok(slice::from_raw_parts(self.ptr as *const T, len))
     }
   }
 
   pub fn try_as_mut_slice<T: Sized + Copy>(&mut self) -> Result<&mut [T], Error> {
-    if self.len() % mem::size_of::<T>() != 0 {
-      // TODO: better error
-      return Err(Error::OutOfMemory)
-    }
+    try!(self.check_writable());
+    let len = try!(self.check_typed::<T>());
 
     unsafe {
-      let p = self.as_mut_ptr();
-      let pt = mem::transmute::<*mut u8, *mut T>(p);
-      Ok(slice::from_raw_parts_mut(pt, self.len() / mem::size_of::<T>()))
+      Ok(slice::from_raw_parts_mut(self.ptr as *mut T, len))
     }
   }
 
   pub fn copy_from<T: Sized + Copy>(&mut self,
                                     vs: &[T]) -> Result<(), Error> {
-    if self.len() != vs.len() * mem::size_of::<T>() {
-      // TODO: better error
-      return Err(Error::OutOfMemory)
+    try!(self.check_writable());
+    let actual = vs.len() * mem::size_of::<T>();
+    if self.len() != actual {
+      return Err(Error::SizeMismatch {
+        expected: self.len(),
+        actual: actual
+      })
+    }
+
+    unsafe {
+      ptr::copy_nonoverlapping(vs.as_ptr() as *const u8, self.ptr, self.len());
+      Ok(())
+    }
+  }
+
+  pub fn copy_from_memory(&mut self, src: &Memory) -> Result<(), Error> {
+    self.copy_from_memory_with(src, &CancelToken::new())
+  }
+
+  /// Like `copy_from_memory`, but checks `token` between chunks of
+  /// `COPY_CHUNK` bytes and stops with `Error::Cancelled`, leaving the
+  /// memory partly copied.
+  pub fn copy_from_memory_with(&mut self, src: &Memory, token: &CancelToken) -> Result<(), Error> {
+    let len = self.len();
+    self.copy_ranges_from_memory_with(src, &[0..len], token)
+  }
+
+  fn check_range(&self, range: &Range<usize>) -> Result<(), Error> {
+    if range.start > range.end || range.end > self.len() {
+      return Err(Error::OutOfRange {
+        start: range.start,
+        end: range.end,
+        len: self.len()
+      })
     }
 
+    Ok(())
+  }
+
+  /// Copies `vs` into the memory starting at byte `offset`.
+  pub fn copy_from_at<T: Sized + Copy>(&mut self,
+                                       offset: usize,
+                                       vs: &[T]) -> Result<(), Error> {
+    try!(self.check_writable());
+    let range = offset..(offset + vs.len() * mem::size_of::<T>());
+    try!(self.check_range(&range));
+
     unsafe {
-      let p = vs.as_ptr();
-      let bytes = mem::transmute::<*const T, *const u8>(p);
-      ptr::copy_nonoverlapping(bytes, self.as_mut_ptr(), self.len());
+      ptr::copy_nonoverlapping(vs.as_ptr() as *const u8, self.ptr.offset(offset as isize), range.len());
       Ok(())
     }
   }
 
-  pub fn into_vec<T: Sized + Copy>(self) -> Result<Vec<T>, Error> {
-    if self.len() % mem::size_of::<T>() != 0 {
-      // TODO: better error
-      return Err(Error::OutOfMemory)
+  /// Copies the bytes in `ranges` from `src`, which must be the same size.
+  pub fn copy_ranges_from_memory(&mut self,
+                                 src: &Memory,
+                                 ranges: &[Range<usize>]) -> Result<(), Error> {
+    self.copy_ranges_from_memory_with(src, ranges, &CancelToken::new())
+  }
+
+  /// Like `copy_ranges_from_memory`, but checks `token` between chunks
+  /// of `COPY_CHUNK` bytes.
+  pub fn copy_ranges_from_memory_with(&mut self,
+                                      src: &Memory,
+                                      ranges: &[Range<usize>],
+                                      token: &CancelToken) -> Result<(), Error> {
+    try!(self.check_writable());
+    if self.len() != src.len() {
+      return Err(Error::SizeMismatch {
+        expected: self.len(),
+        actual: src.len()
+      })
+    }
+
+    for range in ranges {
+      try!(self.check_range(range));
+      let mut start = range.start;
+      while start < range.end {
+        try!(token.check());
+        let end = cmp::min(start + COPY_CHUNK, range.end);
+        self.as_mut_bytes()[start..end].copy_from_slice(&src.as_bytes()[start..end]);
+        start = end;
+      }
+    }
+    Ok(())
+  }
+
+  /// Copies the bytes in `range` into a Vec<T>.
+  pub fn range_to_vec<T: Sized + Copy>(&self, range: Range<usize>) -> Result<Vec<T>, Error> {
+    try!(self.check_range(&range));
+
+    let size = mem::size_of::<T>();
+    if size == 0 || range.len() % size != 0 {
+      return Err(Error::InvalidLength {
+        len: range.len(),
+        elem_size: size
+      })
     }
 
+    let len = range.len() / size;
+    let mut vec: Vec<T> = Vec::with_capacity(len);
     unsafe {
-      let mut buf: Box<[u8]> = self.buf.clone();
-      let buf_ptr = mem::transmute::<*mut u8, *mut T>(buf.as_mut_ptr());
-      let len = self.len() / mem::size_of::<T>();
-      let vec = Vec::from_raw_parts(buf_ptr, len, len);
-      mem::forget(buf);
-      Ok(vec)
+      ptr::copy_nonoverlapping(self.ptr.offset(range.start as isize), vec.as_mut_ptr() as *mut u8, range.len());
+      vec.set_len(len);
     }
+    Ok(vec)
+  }
+
+  pub fn to_vec<T: Sized + Copy>(&self) -> Result<Vec<T>, Error> {
+    let len = try!(self.check_len::<T>());
+
+    // Copy bytewise into a fresh Vec<T>, which has the right layout for T
+    // no matter how this memory is aligned
+    let mut vec: Vec<T> = Vec::with_capacity(len);
+    unsafe {
+      ptr::copy_nonoverlapping(self.ptr, vec.as_mut_ptr() as *mut u8, self.len());
+      vec.set_len(len);
+    }
+    Ok(vec)
+  }
+
+  pub(crate) fn set_allocator(&mut self, allocator: Allocator) {
+    self.allocator = Some(allocator);
+  }
+
+  /// Whether `into_vec` can hand over the allocation without copying.
+  /// Only memory aligned exactly like `T` qualifies, which rules out
+  /// memory from `alloc` and device allocators for every type aligned
+  /// to less than `DEFAULT_ALIGN`.
+  pub fn can_reuse_as<T: Sized>(&self) -> bool {
+    self.mapping.is_none() &&
+      mem::size_of::<T>() != 0 &&
+      self.layout.align() == mem::align_of::<T>() &&
+      self.layout.size() % mem::size_of::<T>() == 0 &&
+      self.len % mem::size_of::<T>() == 0
+  }
+
+  /// Copies the memory through its allocator, failing if the device
+  /// is out of memory. Read-only mappings are shared instead, nobody
+  /// can write to them. Memory without an allocator is copied by the
+  /// global allocator, which aborts on failure just like `Vec` does.
+  pub fn try_clone(&self) -> Result<Memory, Error> {
+    if let Some(Mapping::Shared(ref mmap)) = self.mapping {
+      let offset = self.ptr as usize - mmap.as_ptr() as usize;
+      return Ok(Memory::from_mapping(mmap.clone(), offset, self.len))
+    }
+
+    let mut mem = match self.allocator {
+      Some(ref allocator) => try!(allocator.alloc(self.len)),
+      None if self.is_mapped() => Memory::alloc(self.len),
+      None => Memory::alloc_aligned(self.len, self.align())
+    };
+    mem.as_mut_bytes().copy_from_slice(self.as_bytes());
+    Ok(mem)
+  }
+
+  /// Turns the memory into a Vec<T>, reusing the allocation when its
+  /// layout matches the one Vec<T> expects and copying otherwise. See
+  /// `can_reuse_as`.
+  pub fn into_vec<T: Sized + Copy>(mut self) -> Result<Vec<T>, Error> {
+    if !self.can_reuse_as::<T>() {
+      return self.to_vec()
+    }
+
+    if let Some(allocator) = self.allocator.take() {
+      allocator.untrack(self.layout);
+    }
+
+    let size = mem::size_of::<T>();
+    let vec = unsafe { Vec::from_raw_parts(self.ptr as *mut T, self.len / size, self.layout.size() / size) };
+    mem::forget(self);
+    Ok(vec)
+  }
+}
+
+impl Drop for Memory {
+  fn drop(&mut self) {
+    // Mapped memory is unmapped when the mapping itself is dropped
+    if self.layout.size() != 0 && self.mapping.is_none() {
+      match self.allocator.take() {
+        Some(allocator) => allocator.release(self.ptr, self.layout),
+        None => unsafe { alloc::dealloc(self.ptr, self.layout) }
+      }
+    }
+  }
+}
+
+impl fmt::Debug for Memory {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Memory {{ len: {}, align: {}, mapped: {} }}", self.len, self.align(), self.is_mapped())
   }
 }
 
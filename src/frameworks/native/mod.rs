@@ -1,23 +1,38 @@
+mod allocator;
+mod builder;
 mod device;
 mod error;
 mod hardware;
 mod memory;
+mod numa;
+mod threads;
 mod backend;
 
-use futures_cpupool::Builder;
-use ::hardware::Hardware as Hware;
+use std::path::{Path, PathBuf};
 
-pub use self::device::Device;
+pub use self::allocator::{MemoryConfig, MemoryStats};
+pub use self::builder::DeviceBuilder;
+pub use self::device::{Device, Transfer};
 pub use self::hardware::Hardware;
-pub use self::memory::Memory;
+pub use self::memory::{Memory, MapMode, DEFAULT_ALIGN};
 pub use self::error::Error;
 pub use self::backend::Backend;
 
 use framework::Framework as IFramework;
 
-pub struct Framework { }
+pub struct Framework {
+  // Where sysfs is mounted, `/sys` unless testing against a fake tree
+  sysfs_root: PathBuf
+}
 
 impl Framework {
+  /// Framework that discovers hardware below `root` instead of `/sys`.
+  pub fn with_sysfs_root<P: AsRef<Path>>(root: P) -> Framework {
+    Framework {
+      sysfs_root: root.as_ref().to_path_buf()
+    }
+  }
+
   pub fn default_hardware(&self) -> Hardware {
     Hardware::new()
   }
@@ -25,6 +40,18 @@ impl Framework {
   pub fn default_device(&self) -> Device {
     self.new_device(&self.default_hardware()).unwrap()
   }
+
+  /// Builder for a device on `hardware` with its own pool and memory
+  /// settings.
+  pub fn device_builder(&self, hardware: &Hardware) -> DeviceBuilder {
+    DeviceBuilder::new(hardware)
+  }
+
+  pub fn new_device_with_memory(&self,
+                                hardware: &Hardware,
+                                config: MemoryConfig) -> Result<Device, Error> {
+    self.device_builder(hardware).memory(config).build()
+  }
 }
 
 impl IFramework for Framework {
@@ -35,18 +62,24 @@ impl IFramework for Framework {
   fn name() -> &'static str { "native" }
 
   fn new() -> Self where Self: Sized {
-    Framework { }
+    Framework::with_sysfs_root("/sys")
   }
 
+  /// One hardware per NUMA node, or the whole machine if there is no
+  /// node to tell apart.
   fn load_hardwares(&self) -> Result<Vec<Self::H>, Self::Error> {
-    let cpu = Hardware::new();
-    Ok(vec![cpu])
+    let machine = Hardware::detect(&self.sysfs_root);
+    let nodes = numa::read_nodes(&self.sysfs_root.join("devices/system/node"));
+    if nodes.is_empty() {
+      return Ok(vec![machine])
+    }
+
+    Ok(nodes.into_iter().
+       map(|(node, cpus)| Hardware::for_node(&machine, &self.sysfs_root, node, cpus)).
+       collect())
   }
 
   fn new_device(&self, hardware: &Self::H) -> Result<Self::D, Self::Error> {
-    let mut builder = Builder::new();
-    builder.name_prefix(hardware.name());
-
-    Ok(Device::new(hardware.clone(), builder))
+    self.new_device_with_memory(hardware, MemoryConfig::default())
   }
 }
@@ -0,0 +1,114 @@
+use std::fs;
+use std::path::Path;
+
+/// CPUs of each NUMA node listed under `dir`, usually
+/// `/sys/devices/system/node`, ordered by node.
+pub fn read_nodes(dir: &Path) -> Vec<(usize, Vec<usize>)> {
+  let entries = match fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(_) => return Vec::new()
+  };
+
+  let mut nodes: Vec<(usize, Vec<usize>)> = entries.
+    filter_map(|entry| entry.ok()).
+    filter_map(|entry| {
+      let name = entry.file_name().to_string_lossy().into_owned();
+      if !name.starts_with("node") {
+        return None
+      }
+
+      let node = match name[4..].parse() {
+        Ok(node) => node,
+        Err(_) => return None
+      };
+      let cpus = fs::read_to_string(entry.path().join("cpulist")).ok().
+        and_then(|list| parse_cpulist(list.trim()));
+
+      // Memory-only nodes have no CPUs to run a pool on
+      match cpus {
+        Some(ref cpus) if cpus.is_empty() => None,
+        Some(cpus) => Some((node, cpus)),
+        None => None
+      }
+    }).
+    collect();
+  nodes.sort();
+  nodes
+}
+
+/// Parses CPU lists like `0-3,8,10-11`.
+pub fn parse_cpulist(list: &str) -> Option<Vec<usize>> {
+  let mut cpus = Vec::new();
+  if list.is_empty() {
+    return Some(cpus)
+  }
+
+  for range in list.split(',') {
+    let mut bounds = range.splitn(2, '-');
+    let start: usize = match bounds.next().and_then(|start| start.trim().parse().ok()) {
+      Some(start) => start,
+      None => return None
+    };
+    let end: usize = match bounds.next() {
+      Some(end) => match end.trim().parse() {
+        Ok(end) => end,
+        Err(_) => return None
+      },
+      None => start
+    };
+
+    cpus.extend(start..end + 1);
+  }
+
+  Some(cpus)
+}
+
+// Memory policy values from <linux/mempolicy.h>
+#[cfg(target_os = "linux")]
+const MPOL_PREFERRED: usize = 1;
+#[cfg(target_os = "linux")]
+const MPOL_MF_MOVE: usize = 1 << 1;
+
+/// Makes memory the calling thread touches first prefer `node`.
+#[cfg(target_os = "linux")]
+pub fn prefer_node_for_thread(node: usize) {
+  use libc;
+
+  let mask = node_mask(node);
+  unsafe {
+    libc::syscall(libc::SYS_set_mempolicy, MPOL_PREFERRED, mask.as_ptr(), mask.len() * 64 + 1);
+  }
+}
+
+/// Asks the kernel to place the whole pages within `len` bytes at `ptr`
+/// on `node`, moving the ones that are already in use.
+#[cfg(target_os = "linux")]
+pub fn prefer_node(ptr: *mut u8, len: usize, node: usize) {
+  use libc;
+
+  let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+  let start = (ptr as usize + page - 1) / page * page;
+  let end = (ptr as usize + len) / page * page;
+  if start >= end {
+    return
+  }
+
+  let mask = node_mask(node);
+  unsafe {
+    libc::syscall(libc::SYS_mbind, start, end - start, MPOL_PREFERRED,
+                  mask.as_ptr(), mask.len() * 64 + 1, MPOL_MF_MOVE);
+  }
+}
+
+#[cfg(target_os = "linux")]
+fn node_mask(node: usize) -> Vec<u64> {
+  let mut mask = vec![0u64; node / 64 + 1];
+  mask[node / 64] |= 1 << (node % 64);
+  mask
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn prefer_node_for_thread(_node: usize) { }
+
+#[cfg(not(target_os = "linux"))]
+pub fn prefer_node(_ptr: *mut u8, _len: usize, _node: usize) { }
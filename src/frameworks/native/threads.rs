@@ -0,0 +1,40 @@
+// Settings applied from inside pool threads. All of them are best effort,
+// a thread the OS refuses to move or reprioritize keeps running as is.
+
+/// Pins the calling thread to `cpus`. CPUs the process may not use are
+/// left to the kernel to drop.
+#[cfg(target_os = "linux")]
+pub fn pin_thread(cpus: &[usize]) {
+  use libc;
+  use std::mem;
+
+  unsafe {
+    let mut set: libc::cpu_set_t = mem::zeroed();
+    libc::CPU_ZERO(&mut set);
+    for &cpu in cpus {
+      if cpu < libc::CPU_SETSIZE as usize {
+        libc::CPU_SET(cpu, &mut set);
+      }
+    }
+
+    libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set);
+  }
+}
+
+/// Sets the nice value of the calling thread. Raising the priority above
+/// the default usually takes privileges.
+#[cfg(target_os = "linux")]
+pub fn set_priority(nice: i32) {
+  use libc;
+
+  unsafe {
+    let tid = libc::syscall(libc::SYS_gettid);
+    libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, nice);
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_thread(_cpus: &[usize]) { }
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_priority(_nice: i32) { }
@@ -0,0 +1,441 @@
+use std::cmp;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+use std::hash::{Hash, Hasher};
+use std::fmt;
+use std::mem;
+use std::ops::Range;
+use std::thread;
+use std::time::Duration;
+
+use futures::{Future, IntoFuture};
+use futures_cpupool::{CpuPool, CpuFuture, Builder};
+
+use cancel::{CancelToken, COPY_CHUNK};
+use device;
+use profile::{Profiler, Timer};
+use stream::Stream;
+use frameworks::native;
+use super::{Error, Hardware};
+use super::memory::{bytes_of, Arena, Memory};
+
+// Simulated devices are numbered apart from native ones, buffers tell
+// them apart by framework anyway
+static NEXT_DEVICE_ID: AtomicIsize = AtomicIsize::new(0);
+
+/// How a simulated device behaves.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+  // Threads kernels and transfers run on
+  pub threads: usize,
+
+  // Most bytes the arena holds at once
+  pub capacity: Option<usize>,
+
+  // Fixed delay of every transfer
+  pub latency: Duration,
+
+  // Transfer speed in bytes per second, unlimited if none
+  pub bandwidth: Option<usize>,
+
+  // Chance of each transfer to fail, between 0 and 1
+  pub failure_rate: f64,
+
+  // Seed of the random failures, so runs can be replayed
+  pub seed: u64
+}
+
+/// Memories of a transfer, handed back whether it succeeds or not.
+pub type Transfer<D, S> = Box<Future<Item=(D, S),Error=(Error, D, S)>>;
+
+#[derive(Debug, Clone)]
+pub struct Device {
+  id: isize,
+  inner: Arc<Inner>
+}
+
+struct Inner {
+  hardware: Hardware,
+  config: Config,
+  pool: CpuPool,
+  arena: Arc<Arena>,
+  failures: Mutex<Failures>,
+  transfers: AtomicUsize,
+  profiler: RwLock<Option<Arc<Profiler>>>
+}
+
+struct Failures {
+  // Transfers that fail no matter the failure rate
+  forced: usize,
+  rng: u64
+}
+
+impl Default for Config {
+  fn default() -> Config {
+    Config {
+      threads: 2,
+      capacity: None,
+      latency: Duration::from_millis(0),
+      bandwidth: None,
+      failure_rate: 0.0,
+      seed: 0x2545_f491_4f6c_dd1d
+    }
+  }
+}
+
+impl Device {
+  pub fn new(hardware: Hardware, config: Config) -> Device {
+    let mut builder = Builder::new();
+    builder.name_prefix(format!("{}-", hardware.name)).
+      pool_size(config.threads.max(1));
+
+    let inner = Arc::new(Inner {
+      hardware: hardware,
+      config: config,
+      pool: builder.create(),
+      arena: Arc::new(Arena::new(config.capacity)),
+      failures: Mutex::new(Failures {
+        forced: 0,
+        // Xorshift gets stuck on zero
+        rng: config.seed.max(1)
+      }),
+      transfers: AtomicUsize::new(0),
+      profiler: RwLock::new(None)
+    });
+
+    Device {
+      id: NEXT_DEVICE_ID.fetch_add(1, Ordering::SeqCst),
+      inner: inner
+    }
+  }
+
+  pub fn pool(&self) -> &CpuPool { &self.inner.pool }
+
+  pub fn config(&self) -> &Config { &self.inner.config }
+
+  /// Bytes currently allocated in the arena.
+  pub fn memory_used(&self) -> usize { self.inner.arena.used() }
+
+  /// Number of transfers started so far, failed ones included.
+  pub fn transfers(&self) -> usize { self.inner.transfers.load(Ordering::SeqCst) }
+
+  /// Makes the next `count` transfers fail.
+  pub fn fail_next(&self, count: usize) {
+    self.inner.failures.lock().unwrap().forced += count;
+  }
+
+  /// Sends a span for every operation from now on to `profiler`, or
+  /// stops profiling if it is none.
+  pub fn set_profiler(&self, profiler: Option<Arc<Profiler>>) {
+    *self.inner.profiler.write().unwrap() = profiler;
+  }
+
+  pub fn profiler(&self) -> Option<Arc<Profiler>> {
+    self.inner.profiler.read().unwrap().clone()
+  }
+
+  pub fn timer(&self, name: &'static str, bytes: usize) -> Timer {
+    Timer::new(self.profiler(), name, "sim", self.id, bytes)
+  }
+
+  /// Runs the kernel `f` on the device threads.
+  pub fn launch<F, R>(&self, f: F) -> Box<Future<Item=R,Error=Error>>
+    where F: FnOnce() -> Result<R, Error> + Send + 'static, R: Send + 'static {
+    Box::new(self.spawn_timed("launch", 0, f))
+  }
+
+  // Runs `f` on the device threads inside a span
+  fn spawn_timed<F, R, E>(&self, name: &'static str, bytes: usize, f: F) -> CpuFuture<R, E>
+    where F: FnOnce() -> Result<R, E> + Send + 'static, R: Send + 'static, E: Send + 'static {
+    let mut timer = self.timer(name, bytes);
+    self.inner.pool.spawn_fn(move || {
+      timer.start();
+      let res = f();
+      timer.finish();
+      res
+    })
+  }
+
+  /// Copies native memory into `dst`, either whole or only the given
+  /// byte ranges. `token` is checked between chunks of the copy.
+  pub fn upload(&self,
+                dst: Memory,
+                src: native::Memory,
+                ranges: Option<Vec<Range<usize>>>,
+                token: &CancelToken) -> Transfer<Memory, native::Memory> {
+    let inner = self.inner.clone();
+    let token = token.clone();
+    let bytes = transfer_bytes(&ranges, src.len());
+    Box::new(self.spawn_timed("upload", bytes, move || {
+      let mut dst = dst;
+      match inner.copy(dst.as_mut_bytes(), src.as_bytes(), ranges, &token) {
+        Ok(()) => Ok((dst, src)),
+        Err(err) => Err((err, dst, src))
+      }
+    }))
+  }
+
+  /// Copies `src` into native memory of `dst_dev`, either whole or only
+  /// the given byte ranges. `token` is checked between chunks of the copy.
+  pub fn download(&self,
+                  dst_dev: &native::Device,
+                  dst: native::Memory,
+                  src: Memory,
+                  ranges: Option<Vec<Range<usize>>>,
+                  token: &CancelToken) -> Transfer<native::Memory, Memory> {
+    // Read-only mappings cannot take the copy, it goes to fresh memory
+    //   of the native device
+    let (dst, mapped) = if dst.is_read_only() {
+      match device::Device::alloc_memory(dst_dev, dst.len()) {
+        Ok(copy) => (copy, Some(dst)),
+        Err(err) => return Box::new(Err((Error::Native(err), dst, src)).into_future())
+      }
+    } else { (dst, None) };
+
+    let inner = self.inner.clone();
+    let token = token.clone();
+    let bytes = transfer_bytes(&ranges, src.len());
+    Box::new(self.spawn_timed("download", bytes, move || {
+      let mut dst = dst;
+
+      // Bytes outside the ranges have to come along from the mapping
+      if let (Some(mapped), true) = (mapped, ranges.is_some()) {
+        if let Err(err) = dst.copy_from_memory(&mapped) {
+          return Err((Error::Native(err), dst, src))
+        }
+      }
+
+      match inner.copy(dst.as_mut_bytes(), src.as_bytes(), ranges, &token) {
+        Ok(()) => Ok((dst, src)),
+        Err(err) => Err((err, dst, src))
+      }
+    }))
+  }
+
+  /// Copies memory of another simulated device into `dst`, checking
+  /// `token` between chunks.
+  pub fn copy_from_device(&self,
+                          dst: Memory,
+                          src: Memory,
+                          ranges: Option<Vec<Range<usize>>>,
+                          token: &CancelToken) -> Transfer<Memory, Memory> {
+    let inner = self.inner.clone();
+    let token = token.clone();
+    let bytes = transfer_bytes(&ranges, src.len());
+    Box::new(self.spawn_timed("copy_from_device", bytes, move || {
+      let mut dst = dst;
+      match inner.copy(dst.as_mut_bytes(), src.as_bytes(), ranges, &token) {
+        Ok(()) => Ok((dst, src)),
+        Err(err) => Err((err, dst, src))
+      }
+    }))
+  }
+}
+
+fn transfer_bytes(ranges: &Option<Vec<Range<usize>>>, len: usize) -> usize {
+  ranges.as_ref().map(|ranges| ranges.iter().map(|r| r.len()).sum()).unwrap_or(len)
+}
+
+fn check_sizes(expected: usize, actual: usize) -> Result<(), Error> {
+  if expected != actual {
+    return Err(Error::SizeMismatch {
+      expected: expected,
+      actual: actual
+    })
+  }
+
+  Ok(())
+}
+
+impl Inner {
+  // Copies the ranges, or everything, from `src` into `dst` as one
+  //   transfer, checking `token` before every chunk
+  fn copy(&self,
+          dst: &mut [u8],
+          src: &[u8],
+          ranges: Option<Vec<Range<usize>>>,
+          token: &CancelToken) -> Result<(), Error> {
+    try!(check_sizes(dst.len(), src.len()));
+    let ranges = ranges.unwrap_or_else(|| vec![0..dst.len()]);
+    for range in &ranges {
+      if range.start > range.end || range.end > dst.len() {
+        return Err(Error::OutOfRange {
+          start: range.start,
+          end: range.end,
+          len: dst.len()
+        })
+      }
+    }
+
+    try!(self.start_transfer(ranges.iter().map(|r| r.len()).sum()));
+    for range in ranges {
+      let mut start = range.start;
+      while start < range.end {
+        try!(token.check());
+        let end = cmp::min(start + COPY_CHUNK, range.end);
+        self.wait_bandwidth(end - start);
+        dst[start..end].copy_from_slice(&src[start..end]);
+        start = end;
+      }
+    }
+
+    Ok(())
+  }
+
+  // Waits as long as moving `bytes` takes and fails it if it is up
+  fn transfer(&self, bytes: usize) -> Result<(), Error> {
+    try!(self.start_transfer(bytes));
+    self.wait_bandwidth(bytes);
+    Ok(())
+  }
+
+  // Waits out the latency of a new transfer and fails it if it is up
+  fn start_transfer(&self, bytes: usize) -> Result<(), Error> {
+    self.transfers.fetch_add(1, Ordering::SeqCst);
+    if self.config.latency > Duration::from_millis(0) {
+      thread::sleep(self.config.latency);
+    }
+
+    if self.should_fail() {
+      return Err(Error::TransferFailed { bytes: bytes })
+    }
+
+    Ok(())
+  }
+
+  fn wait_bandwidth(&self, bytes: usize) {
+    if let Some(bandwidth) = self.config.bandwidth {
+      thread::sleep(Duration::from_secs_f64(bytes as f64 / bandwidth.max(1) as f64));
+    }
+  }
+
+  fn should_fail(&self) -> bool {
+    let mut failures = self.failures.lock().unwrap();
+    if failures.forced > 0 {
+      failures.forced -= 1;
+      return true
+    }
+
+    if self.config.failure_rate <= 0.0 {
+      return false
+    }
+
+    let mut x = failures.rng;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    failures.rng = x;
+
+    // Top 53 bits give a uniform float in [0, 1)
+    ((x >> 11) as f64 / (1u64 << 53) as f64) < self.config.failure_rate
+  }
+}
+
+impl device::Device for Device {
+  type H = Hardware;
+  type M = Memory;
+  type Error = Error;
+
+  fn id(&self) -> isize { self.id }
+  fn hardware(&self) -> &Self::H { &self.inner.hardware }
+  fn alloc_memory(&self, size: usize) -> Result<Self::M, Self::Error> {
+    let mut timer = self.timer("alloc_memory", size);
+    timer.start();
+    let res = Arena::alloc(&self.inner.arena, size);
+    timer.finish();
+    res
+  }
+
+  // Runs right away on the calling thread, so it is neither delayed nor
+  // failed like transfers are
+  fn memory_from_vec<T: Send + Copy + Sized + 'static>(&self,
+                                                       vec: Vec<T>) -> Result<Self::M, Self::Error> {
+    let bytes = bytes_of(&vec);
+    let mut mem = try!(self.alloc_memory(bytes.len()));
+    try!(mem.write_bytes(0, bytes));
+    Ok(mem)
+  }
+
+  fn sync_from_vec<T: Send + Copy + Sized + 'static>(&self,
+                                                     mem: Self::M,
+                                                     vec: Vec<T>) -> Box<Future<Item=Self::M,Error=Self::Error>> {
+    let inner = self.inner.clone();
+    let bytes = vec.len() * mem::size_of::<T>();
+    Box::new(self.spawn_timed("sync_from_vec", bytes, move || {
+      let mut mem = mem;
+      let bytes = bytes_of(&vec);
+      try!(check_sizes(mem.len(), bytes.len()));
+      try!(inner.transfer(bytes.len()));
+      try!(mem.write_bytes(0, bytes));
+      Ok(mem)
+    }))
+  }
+
+  fn sync_to_vec<T: Send + Copy + Sized + 'static>(&self,
+                                                   mem: Self::M) -> Box<Future<Item=(Self::M, Vec<T>),Error=Self::Error>> {
+    let inner = self.inner.clone();
+    Box::new(self.spawn_timed("sync_to_vec", mem.len(), move || {
+      try!(inner.transfer(mem.len()));
+      let vec: Vec<T> = try!(mem.range_to_vec(0..mem.len()));
+      Ok((mem, vec))
+    }))
+  }
+
+  fn write_range<T: Send + Copy + Sized + 'static>(&self,
+                                                   mem: Self::M,
+                                                   offset: usize,
+                                                   vec: Vec<T>) -> Box<Future<Item=Self::M,Error=Self::Error>> {
+    let inner = self.inner.clone();
+    let bytes = vec.len() * mem::size_of::<T>();
+    Box::new(self.spawn_timed("write_range", bytes, move || {
+      let mut mem = mem;
+      let bytes = bytes_of(&vec);
+      try!(inner.transfer(bytes.len()));
+      try!(mem.write_bytes(offset * mem::size_of::<T>(), bytes));
+      Ok(mem)
+    }))
+  }
+
+  fn read_range<T: Send + Copy + Sized + 'static>(&self,
+                                                  mem: Self::M,
+                                                  range: Range<usize>) -> Box<Future<Item=(Self::M, Vec<T>),Error=Self::Error>> {
+    let size = mem::size_of::<T>();
+    let inner = self.inner.clone();
+    Box::new(self.spawn_timed("read_range", range.len() * size, move || {
+      let range = (range.start * size)..(range.end * size);
+      try!(inner.transfer(range.len()));
+      let vec: Vec<T> = try!(mem.range_to_vec(range));
+      Ok((mem, vec))
+    }))
+  }
+
+  fn into_vec<T: Send + Copy + Sized + 'static>(&self,
+                                                mem: Self::M) -> Box<Future<Item=Vec<T>,Error=Self::Error>> {
+    Box::new(self.sync_to_vec(mem).map(|(_, vec)| vec))
+  }
+
+  fn new_stream(&self) -> Stream {
+    Stream::new(&format!("{}-stream-", self.inner.hardware.name))
+  }
+}
+
+impl PartialEq for Device {
+  fn eq(&self, o: &Self) -> bool {
+    self.id == o.id
+  }
+}
+
+impl Eq for Device { }
+
+impl Hash for Device {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.id.hash(state);
+  }
+}
+
+impl fmt::Debug for Inner {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Inner {{ hardware: {:?}, config: {:?} }}", &self.hardware, &self.config)
+  }
+}
+
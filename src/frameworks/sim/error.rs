@@ -0,0 +1,65 @@
+use std::error;
+use std::fmt;
+
+use cancel::Cancelled;
+use frameworks::native;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+  // Allocation would go over the arena capacity
+  OutOfMemory { requested: usize, available: usize },
+
+  // Source and destination of a copy differ in size, in bytes
+  SizeMismatch { expected: usize, actual: usize },
+
+  // Memory length in bytes is not a multiple of the element size
+  InvalidLength { len: usize, elem_size: usize },
+
+  // Byte range reaches past the end of the memory
+  OutOfRange { start: usize, end: usize, len: usize },
+
+  // Transfer failed on purpose, as configured
+  TransferFailed { bytes: usize },
+
+  // Native side of a transfer failed
+  Native(native::Error),
+
+  // Transfer stopped because its cancel token was set
+  Cancelled
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Error::OutOfMemory { requested, available } =>
+        write!(f, "out of memory: requested {} bytes, {} bytes available", requested, available),
+      Error::SizeMismatch { expected, actual } =>
+        write!(f, "size mismatch: expected {} bytes, got {} bytes", expected, actual),
+      Error::InvalidLength { len, elem_size } =>
+        write!(f, "invalid length: {} bytes is not a multiple of the element size {}", len, elem_size),
+      Error::OutOfRange { start, end, len } =>
+        write!(f, "out of range: bytes {}..{} of memory with {} bytes", start, end, len),
+      Error::TransferFailed { bytes } =>
+        write!(f, "simulated failure transferring {} bytes", bytes),
+      Error::Native(ref err) => write!(f, "native device error: {}", err),
+      Error::Cancelled => write!(f, "transfer was cancelled")
+    }
+  }
+}
+
+impl error::Error for Error {
+  fn source(&self) -> Option<&(error::Error + 'static)> {
+    match *self {
+      Error::Native(ref err) => Some(err),
+      _ => None
+    }
+  }
+}
+
+impl From<native::Error> for Error {
+  fn from(err: native::Error) -> Error { Error::Native(err) }
+}
+
+impl From<Cancelled> for Error {
+  fn from(_: Cancelled) -> Error { Error::Cancelled }
+}
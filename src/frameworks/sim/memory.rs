@@ -0,0 +1,162 @@
+use std::fmt;
+use std::mem;
+use std::ops::Range;
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+use super::Error;
+use memory;
+
+// Every element type buffers hold is aligned to at most this many bytes
+const ALIGN: usize = 8;
+
+/// Memory of a simulated device. It lives in the arena of its device and
+/// never shares an allocation with host vectors, so every transfer
+/// between the two is an actual copy.
+pub struct Memory {
+  // Words keep the bytes aligned for any element type
+  data: Vec<u64>,
+  len: usize,
+  arena: Arc<Arena>
+}
+
+/// Bookkeeping of the bytes a simulated device has handed out.
+#[derive(Debug)]
+pub struct Arena {
+  capacity: Option<usize>,
+  used: Mutex<usize>
+}
+
+impl Arena {
+  pub fn new(capacity: Option<usize>) -> Arena {
+    Arena {
+      capacity: capacity,
+      used: Mutex::new(0)
+    }
+  }
+
+  pub fn capacity(&self) -> Option<usize> { self.capacity }
+
+  pub fn used(&self) -> usize { *self.used.lock().unwrap() }
+
+  /// Allocates zeroed memory, failing if the arena cannot hold it.
+  pub fn alloc(arena: &Arc<Arena>, len: usize) -> Result<Memory, Error> {
+    {
+      let mut used = arena.used.lock().unwrap();
+      if let Some(capacity) = arena.capacity {
+        let available = capacity.saturating_sub(*used);
+        if len > available {
+          return Err(Error::OutOfMemory {
+            requested: len,
+            available: available
+          })
+        }
+      }
+      *used += len;
+    }
+
+    Ok(Memory {
+      data: vec![0; (len + ALIGN - 1) / ALIGN],
+      len: len,
+      arena: arena.clone()
+    })
+  }
+}
+
+impl Memory {
+  pub fn len(&self) -> usize { self.len }
+
+  pub fn is_empty(&self) -> bool { self.len == 0 }
+
+  pub fn as_bytes(&self) -> &[u8] {
+    unsafe { slice::from_raw_parts(self.data.as_ptr() as *const u8, self.len) }
+  }
+
+  pub fn as_mut_bytes(&mut self) -> &mut [u8] {
+    unsafe { slice::from_raw_parts_mut(self.data.as_mut_ptr() as *mut u8, self.len) }
+  }
+
+  fn check_len<T: Sized>(&self) -> Result<usize, Error> {
+    let size = mem::size_of::<T>();
+
+    if size == 0 || self.len % size != 0 || mem::align_of::<T>() > ALIGN {
+      return Err(Error::InvalidLength {
+        len: self.len,
+        elem_size: size
+      })
+    }
+
+    Ok(self.len / size)
+  }
+
+  pub fn try_as_slice<T: Sized + Copy>(&self) -> Result<&[T], Error> {
+    let len = try!(self.check_len::<T>());
+    unsafe { Ok(slice::from_raw_parts(self.data.as_ptr() as *const T, len)) }
+  }
+
+  pub fn try_as_mut_slice<T: Sized + Copy>(&mut self) -> Result<&mut [T], Error> {
+    let len = try!(self.check_len::<T>());
+    unsafe { Ok(slice::from_raw_parts_mut(self.data.as_mut_ptr() as *mut T, len)) }
+  }
+
+  pub fn check_range(&self, range: &Range<usize>) -> Result<(), Error> {
+    if range.start > range.end || range.end > self.len {
+      return Err(Error::OutOfRange {
+        start: range.start,
+        end: range.end,
+        len: self.len
+      })
+    }
+
+    Ok(())
+  }
+
+  /// Copies `bytes` into the memory starting at byte `offset`.
+  pub fn write_bytes(&mut self, offset: usize, bytes: &[u8]) -> Result<(), Error> {
+    let range = offset..(offset + bytes.len());
+    try!(self.check_range(&range));
+    self.as_mut_bytes()[range].copy_from_slice(bytes);
+    Ok(())
+  }
+
+  /// Copies the bytes in `range` into a Vec<T>.
+  pub fn range_to_vec<T: Sized + Copy>(&self, range: Range<usize>) -> Result<Vec<T>, Error> {
+    try!(self.check_range(&range));
+
+    let size = mem::size_of::<T>();
+    if size == 0 || range.len() % size != 0 {
+      return Err(Error::InvalidLength {
+        len: range.len(),
+        elem_size: size
+      })
+    }
+
+    let len = range.len() / size;
+    let mut vec: Vec<T> = Vec::with_capacity(len);
+    unsafe {
+      slice::from_raw_parts_mut(vec.as_mut_ptr() as *mut u8, range.len()).
+        copy_from_slice(&self.as_bytes()[range]);
+      vec.set_len(len);
+    }
+    Ok(vec)
+  }
+}
+
+/// Bytes of the elements in `vs`.
+pub fn bytes_of<T: Sized + Copy>(vs: &[T]) -> &[u8] {
+  unsafe { slice::from_raw_parts(vs.as_ptr() as *const u8, vs.len() * mem::size_of::<T>()) }
+}
+
+impl Drop for Memory {
+  fn drop(&mut self) {
+    *self.arena.used.lock().unwrap() -= self.len;
+  }
+}
+
+impl fmt::Debug for Memory {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Memory {{ len: {} }}", self.len)
+  }
+}
+
+impl memory::Memory for Memory { }
@@ -0,0 +1,96 @@
+// Simulated accelerator for testing. Its memory lives in an arena apart
+// from host memory and every transfer is a real copy on its own threads,
+// optionally slowed down or failed, so buffer syncs between devices can
+// be exercised on machines with nothing but a CPU.
+
+mod device;
+mod error;
+mod memory;
+
+pub use self::device::{Config, Device, Transfer};
+pub use self::error::Error;
+pub use self::memory::{Arena, Memory};
+
+use backend;
+use framework::Framework as IFramework;
+use hardware;
+
+#[derive(Debug, Clone)]
+pub struct Hardware {
+  name: String,
+  compute_units: usize
+}
+
+impl Hardware {
+  pub fn new(compute_units: usize) -> Hardware {
+    Hardware {
+      name: "sim".to_string(),
+      compute_units: compute_units
+    }
+  }
+}
+
+impl hardware::Hardware for Hardware {
+  fn name(&self) -> &str { &self.name }
+  fn hardware_type(&self) -> hardware::HardwareType { hardware::HardwareType::ACCELERATOR }
+  fn compute_units(&self) -> usize { self.compute_units }
+}
+
+pub struct Framework {
+  config: Config
+}
+
+impl Framework {
+  /// Framework whose devices behave as `config` says.
+  pub fn with_config(config: Config) -> Framework {
+    Framework {
+      config: config
+    }
+  }
+
+  pub fn default_device(&self) -> Device {
+    Device::new(Hardware::new(self.config.threads), self.config)
+  }
+}
+
+impl IFramework for Framework {
+  type H = Hardware;
+  type D = Device;
+  type Error = Error;
+
+  fn name() -> &'static str { "sim" }
+
+  fn new() -> Self where Self: Sized {
+    Framework::with_config(Config::default())
+  }
+
+  fn load_hardwares(&self) -> Result<Vec<Self::H>, Self::Error> {
+    Ok(vec![Hardware::new(self.config.threads)])
+  }
+
+  fn new_device(&self, hardware: &Self::H) -> Result<Self::D, Self::Error> {
+    Ok(Device::new(hardware.clone(), self.config))
+  }
+}
+
+pub struct Backend {
+  device: Device
+}
+
+impl Backend {
+  pub fn default() -> Backend {
+    Backend {
+      device: Framework::new().default_device()
+    }
+  }
+
+  pub fn new(device: Device) -> Backend {
+    Backend {
+      device: device
+    }
+  }
+}
+
+impl backend::Backend<Framework> for Backend {
+  fn device(&self) -> &Device { &self.device }
+}
@@ -6,8 +6,62 @@ pub enum HardwareType {
   OTHER
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CacheType {
+  Data,
+  Instruction,
+  Unified
+}
+
+/// One level of the cache hierarchy, as seen by a single compute unit.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Cache {
+  pub level: u8,
+  pub cache_type: CacheType,
+
+  // Size in bytes
+  pub size: usize,
+  pub line_size: Option<usize>
+}
+
+/// Vector instruction sets a device can run kernels with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Simd {
+  SSE,
+  SSE2,
+  SSE3,
+  SSSE3,
+  SSE41,
+  SSE42,
+  AVX,
+  AVX2,
+  FMA,
+  AVX512F,
+  NEON
+}
+
 pub trait Hardware {
   fn name(&self) -> &str;
   fn hardware_type(&self) -> HardwareType;
+
+  /// Number of units that can run work at the same time, counting
+  /// hardware threads on CPUs.
   fn compute_units(&self) -> usize;
+
+  /// Number of physical cores, which is below `compute_units` when cores
+  /// run several hardware threads.
+  fn physical_units(&self) -> usize { self.compute_units() }
+
+  fn model_name(&self) -> Option<&str> { None }
+  fn caches(&self) -> &[Cache] { &[] }
+  fn simd(&self) -> &[Simd] { &[] }
+
+  fn has_simd(&self, simd: Simd) -> bool { self.simd().contains(&simd) }
+
+  fn cache_size(&self, level: u8) -> Option<usize> {
+    self.caches().iter().
+      filter(|cache| cache.level == level && cache.cache_type != CacheType::Instruction).
+      map(|cache| cache.size).
+      next()
+  }
 }
@@ -1,23 +1,47 @@
 extern crate futures;
 extern crate futures_cpupool;
+extern crate half;
+#[cfg(target_os = "linux")]
+extern crate libc;
+extern crate num_complex;
+extern crate memmap2;
+extern crate serde_json;
+extern crate zip;
 
 pub mod backend;
 pub mod hardware;
 pub mod framework;
 pub mod memory;
 pub mod device;
+#[macro_use]
+pub mod data_type;
 pub mod buffer;
+pub mod cancel;
 pub mod frameworks;
 pub mod lock;
+pub mod profile;
+pub mod stream;
+pub mod tensor;
+pub mod convert;
+pub mod formats;
 
 pub use backend::Backend;
-pub use hardware::Hardware;
+pub use hardware::{Hardware, HardwareType, Cache, CacheType, Simd};
 pub use framework::Framework;
 pub use memory::Memory;
 pub use device::Device;
-pub use buffer::{Buffer, BufferDevice};
+pub use stream::{Stream, Event};
+pub use cancel::{CancelToken, Cancelled};
+pub use profile::{Profiler, Collector, Span, Summary};
+pub use data_type::{DataType, Element};
+pub use half::{bf16, f16};
+pub use num_complex::{Complex32, Complex64};
+pub use buffer::{Buffer, ReadBuffer, BufferView, BufferViewMut, BufferDevice, AnyBuffer};
+pub use tensor::{Tensor, TensorView, Shape};
 
 pub use frameworks::native;
+#[cfg(feature = "sim")]
+pub use frameworks::sim;
 
 #[cfg(test)]
 mod test {
@@ -36,4 +60,988 @@ mod test {
 
     assert_eq!(nv, vec![23.0, 45.5, 54.2, 42.0]);
   }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_sync() {
+    let backend = native::Backend::default();
+    let dev = backend.device();
+    let bdev = BufferDevice::from(dev);
+    let buf: Buffer<f32> = Buffer::new(dev, 3).unwrap();
+    assert!(buf.is_latest(&bdev));
+
+    let buf = buf.sync_from_vec(vec![1.0, 2.0, 3.0], dev).wait().unwrap();
+    let buf = buf.sync(&bdev).wait().unwrap();
+    assert!(buf.is_latest(&bdev));
+
+    let (_, nv) = buf.sync_to_vec(dev).wait().unwrap();
+    assert_eq!(nv, vec![1.0, 2.0, 3.0]);
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_multi_device() {
+    let framework = native::Framework::new();
+    let hardware = framework.default_hardware();
+    let dev1 = framework.new_device(&hardware).unwrap();
+    let dev2 = framework.new_device(&hardware).unwrap();
+    let bdev1 = BufferDevice::from(&dev1);
+    let bdev2 = BufferDevice::from(&dev2);
+    assert!(dev1 != dev2);
+    assert!(dev1.id() != dev2.id());
+
+    let buf: Buffer<u64> = Buffer::new(&dev1, 3).unwrap();
+    let buf = buf.sync_from_vec(vec![1, 2, 3], &dev1).wait().unwrap();
+    let (buf, nv) = buf.sync_to_vec(&dev2).wait().unwrap();
+    assert_eq!(nv, vec![1, 2, 3]);
+    assert!(buf.has_copy(&bdev1) && buf.has_copy(&bdev2));
+    assert!(buf.is_latest(&bdev1) && buf.is_latest(&bdev2));
+
+    // A failed write only takes a copy out of date while another one is
+    let mut buf = buf;
+    buf.mark_stale(&bdev2);
+    assert!(buf.is_latest(&bdev1) && !buf.is_latest(&bdev2));
+    buf.mark_stale(&bdev1);
+    assert!(buf.is_latest(&bdev1));
+
+    let buf = buf.sync_from_vec(vec![4, 5, 6], &dev2).wait().unwrap();
+    assert!(!buf.is_latest(&bdev1));
+    let (_, nv) = buf.sync_to_vec(&dev1).wait().unwrap();
+    assert_eq!(nv, vec![4, 5, 6]);
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_lock() {
+    use std::thread;
+    use std::time::Duration;
+
+    let backend = native::Backend::default();
+    let dev = backend.device().clone();
+    let buf: Buffer<f32> = Buffer::new(&dev, 2).unwrap();
+    let handle = buf.handle();
+    assert!(handle.try_lock().is_err());
+
+    let next = Buffer::lock(&handle);
+    let holder = thread::spawn(move || {
+      thread::sleep(Duration::from_millis(20));
+      buf.sync_from_vec(vec![1.0, 2.0], dev).wait().unwrap();
+    });
+
+    let (buf, nv) = next.and_then(|buf| buf.sync_to_vec(backend.device())).wait().unwrap();
+    holder.join().unwrap();
+    assert_eq!(nv, vec![1.0, 2.0]);
+    assert!(handle.try_lock().is_err());
+
+    drop(buf);
+    assert!(handle.try_lock().is_ok());
+
+    // Futures polled over and over hold a single waiter slot each
+    let lock = lock::Lock::new(0);
+    let guard = lock.try_lock().unwrap();
+    let mut writer = lock.lock();
+    let mut reader = lock.read();
+    futures::future::lazy(|| {
+      for _ in 0..100 {
+        assert!(writer.poll().unwrap().is_not_ready());
+        assert!(reader.poll().unwrap().is_not_ready());
+      }
+      Ok::<_, ()>(())
+    }).wait().unwrap();
+    assert_eq!(lock.waiting(), 2);
+
+    drop(reader);
+    assert_eq!(lock.waiting(), 1);
+    drop(guard);
+    assert!(writer.wait().is_ok());
+    assert_eq!(lock.waiting(), 0);
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_shared_read() {
+    let framework = native::Framework::new();
+    let hardware = framework.default_hardware();
+    let dev1 = framework.new_device(&hardware).unwrap();
+    let dev2 = framework.new_device(&hardware).unwrap();
+
+    let buf: Buffer<f32> = Buffer::new(&dev1, 2).unwrap();
+    let buf = buf.sync_from_vec(vec![1.0, 2.0], &dev1).wait().unwrap();
+    let handle = buf.handle();
+
+    let read1 = buf.share();
+    let read2 = read1.clone();
+    let read3 = ReadBuffer::from_lock(handle.clone()).unwrap();
+    assert!(handle.try_lock().is_err());
+
+    let writer = Buffer::lock(&handle);
+    let read1 = read1.sync(&BufferDevice::from(&dev1)).wait().unwrap();
+    assert!(read2.clone().sync(&BufferDevice::from(&dev2)).wait().is_err());
+    assert_eq!(read1.native_memory(&dev1).unwrap().try_as_slice::<f32>().unwrap(), &[1.0, 2.0]);
+
+    let read2 = read2.try_unshare().err().unwrap();
+    drop(read1);
+    drop(read2);
+    let buf = read3.try_unshare().ok().unwrap();
+    drop(buf);
+
+    let (_, nv) = writer.and_then(|buf| buf.sync_to_vec(&dev2)).wait().unwrap();
+    assert_eq!(nv, vec![1.0, 2.0]);
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_memory_alignment() {
+    let mem = native::Memory::alloc(24);
+    assert_eq!(mem.align(), native::DEFAULT_ALIGN);
+    assert!(mem.is_aligned_for::<f64>());
+    assert_eq!(mem.try_as_slice::<f64>().unwrap(), &[0.0, 0.0, 0.0]);
+    assert!(mem.try_as_slice::<[u8; 5]>().is_err());
+
+    let mut mem = native::Memory::alloc_aligned(16, 8);
+    mem.copy_from(&[1u64, 2]).unwrap();
+    assert_eq!(mem.try_clone().unwrap().into_vec::<u64>().unwrap(), vec![1, 2]);
+    assert!(native::Memory::alloc_aligned(0, 16).try_as_slice::<u128>().unwrap().is_empty());
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_caching_allocator() {
+    let framework = native::Framework::new();
+    let config = native::MemoryConfig {
+      max_cached_bytes: 4096,
+      .. native::MemoryConfig::default()
+    };
+    let dev = framework.new_device_with_memory(&framework.default_hardware(), config).unwrap();
+
+    let mut mem = dev.alloc_memory(1000).unwrap();
+    mem.copy_from(&[7u8; 1000]).unwrap();
+    drop(mem);
+    assert_eq!(dev.memory_stats().cached_bytes, 1024);
+
+    let mem = dev.alloc_memory(900).unwrap();
+    assert_eq!(mem.as_bytes(), &[0u8; 900][..]);
+    let stats = dev.memory_stats();
+    assert_eq!((stats.hits, stats.misses, stats.cached_bytes), (1, 1, 0));
+
+    // Only the first block fits under the cache limit
+    let big = dev.alloc_memory(4000).unwrap();
+    drop(mem);
+    drop(big);
+    assert_eq!(dev.memory_stats().cached_blocks, 1);
+
+    assert_eq!(dev.trim(), 1024);
+    assert_eq!(dev.memory_stats().cached_bytes, 0);
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_memory_budget() {
+    let framework = native::Framework::new();
+    let config = native::MemoryConfig {
+      budget: Some(2048),
+      .. native::MemoryConfig::default()
+    };
+    let dev = framework.new_device_with_memory(&framework.default_hardware(), config).unwrap();
+
+    let small = dev.alloc_memory(512).unwrap();
+    let big = dev.alloc_memory(1024).unwrap();
+    assert_eq!(dev.memory_stats().in_use_bytes, 1536);
+    assert_eq!(dev.memory_stats().available(), Some(512));
+
+    match dev.alloc_memory(1024) {
+      Err(native::Error::OutOfMemory { requested, available }) => {
+        assert_eq!((requested, available), (1024, 512));
+      },
+      r => panic!("expected out of memory, got {:?}", r)
+    }
+
+    // Cached blocks are freed to make room
+    drop(big);
+    drop(small);
+    let mem = dev.alloc_memory(2048).unwrap();
+    assert_eq!(dev.memory_stats().cached_bytes, 0);
+    assert_eq!(dev.memory_stats().in_use_bytes, 2048);
+
+    // Clones are allocated within the budget too
+    match mem.try_clone() {
+      Err(native::Error::OutOfMemory { requested: 2048, .. }) => (),
+      r => panic!("expected out of memory, got {:?}", r)
+    }
+
+    // Allocations the system refuses report what the budget has left
+    let config = native::MemoryConfig {
+      budget: Some(1 << 63),
+      .. native::MemoryConfig::default()
+    };
+    let dev = framework.new_device_with_memory(&framework.default_hardware(), config).unwrap();
+    match dev.alloc_memory(1 << 62) {
+      Err(native::Error::OutOfMemory { requested, available }) => {
+        assert_eq!((requested, available), (1 << 62, 1 << 63));
+      },
+      r => panic!("expected out of memory, got {:?}", r)
+    }
+
+    // Sizes with no power of two size class fail instead of overflowing
+    match dev.alloc_memory(usize::MAX / 2 + 2) {
+      Err(native::Error::OutOfMemory { requested, available }) => {
+        assert_eq!((requested, available), (usize::MAX / 2 + 2, 1 << 63));
+      },
+      r => panic!("expected out of memory, got {:?}", r)
+    }
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_errors() {
+    use std::error::Error;
+
+    let backend = native::Backend::default();
+    let dev = backend.device();
+    let buf: Buffer<f32> = Buffer::new(dev, 2).unwrap();
+
+    let err = buf.sync_from_vec(vec![1.0], dev).wait().err().unwrap();
+    assert_eq!(err.to_string(), "size mismatch: expected 2 elements, got 1");
+
+    let err: buffer::Error = native::Memory::alloc(6).try_as_slice::<f32>().err().unwrap().into();
+    assert_eq!(err.to_string(), "native device error");
+    assert_eq!(err.source().unwrap().to_string(),
+               "invalid length: 6 bytes is not a multiple of the element size 4");
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_zero_copy() {
+    let backend = native::Backend::default();
+    let dev = backend.device();
+
+    let vec: Vec<f64> = vec![1.0, 2.0, 3.0];
+    let ptr = vec.as_ptr() as *const u8;
+    let buf = Buffer::from_vec(dev, vec).unwrap();
+    assert_eq!(buf.native_memory(dev).unwrap().as_ptr(), ptr);
+    assert_eq!(dev.memory_stats().in_use_bytes, 24);
+
+    let vec = buf.into_vec(dev).wait().unwrap();
+    assert_eq!(vec.as_ptr() as *const u8, ptr);
+    assert_eq!(vec, vec![1.0, 2.0, 3.0]);
+    assert_eq!(dev.memory_stats().in_use_bytes, 0);
+
+    let buf: Buffer<f64> = Buffer::new(dev, 3).unwrap();
+    let buf = buf.sync_from_vec(vec, dev).wait().unwrap();
+    assert_eq!(buf.native_memory(dev).unwrap().as_ptr(), ptr);
+
+    // Spare capacity comes along instead of being shrunk away
+    let mut vec: Vec<f64> = Vec::with_capacity(8);
+    vec.extend_from_slice(&[1.0, 2.0]);
+    let ptr = vec.as_ptr();
+    let buf = Buffer::from_vec(dev, vec).unwrap();
+    assert_eq!(buf.native_memory(dev).unwrap().as_ptr(), ptr as *const u8);
+    let vec = buf.into_vec(dev).wait().unwrap();
+    assert_eq!((vec.as_ptr(), vec.capacity()), (ptr, 8));
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_ranges() {
+    let framework = native::Framework::new();
+    let hardware = framework.default_hardware();
+    let dev1 = framework.new_device(&hardware).unwrap();
+    let dev2 = framework.new_device(&hardware).unwrap();
+    let bdev1 = BufferDevice::from(&dev1);
+    let bdev2 = BufferDevice::from(&dev2);
+
+    let buf: Buffer<i32> = Buffer::from_vec(&dev1, vec![0, 1, 2, 3, 4, 5]).unwrap();
+    let buf = buf.sync(&bdev2).wait().unwrap();
+
+    let buf = buf.write_range(1, &[10, 20]).wait().unwrap();
+    assert!(buf.is_latest(&bdev1) != buf.is_latest(&bdev2));
+
+    let buf = buf.write_range(4, &[40]).wait().unwrap();
+    let (buf, vec) = buf.read_range(1..5).wait().unwrap();
+    assert_eq!(vec, vec![10, 20, 3, 40]);
+
+    let stale = if buf.is_latest(&bdev1) { &dev2 } else { &dev1 };
+    let bstale = BufferDevice::from(stale);
+    assert!(buf.is_range_latest(&bstale, &(0..1)));
+    assert!(buf.is_range_latest(&bstale, &(3..4)));
+    assert!(!buf.is_range_latest(&bstale, &(2..4)));
+    assert!(!buf.is_range_latest(&bstale, &(4..6)));
+
+    let (buf, vec) = buf.sync_to_vec(stale).wait().unwrap();
+    assert_eq!(vec, vec![0, 10, 20, 3, 40, 5]);
+    assert!(buf.is_latest(&bdev1) && buf.is_latest(&bdev2));
+    assert!(buf.read_range(5..7).wait().is_err());
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_views() {
+    let framework = native::Framework::new();
+    let hardware = framework.default_hardware();
+    let dev1 = framework.new_device(&hardware).unwrap();
+    let dev2 = framework.new_device(&hardware).unwrap();
+
+    let buf: Buffer<u32> = Buffer::from_vec(&dev1, vec![0, 1, 2, 3, 4, 5]).unwrap();
+    let buf = buf.sync(&BufferDevice::from(&dev2)).wait().unwrap();
+    let buf = buf.write_range(0, &[9]).wait().unwrap();
+    let handle = buf.handle();
+
+    let rows = buf.view(2, 4).unwrap();
+    let row = rows.view(1, 2).unwrap();
+    assert_eq!(row.range(), 3..5);
+    assert!(rows.view(3, 2).is_err());
+    assert_eq!(rows.native_slice(&dev1).unwrap(), &[2, 3, 4, 5]);
+
+    // Both copies are up to date outside of the written element
+    let row = row.sync(&BufferDevice::from(&dev1)).wait().unwrap();
+    let row = row.sync(&BufferDevice::from(&dev2)).wait().unwrap();
+    assert_eq!(row.native_slice(&dev2).unwrap(), &[3, 4]);
+    assert!(rows.parent().view(0, 2).unwrap().sync(&BufferDevice::from(&dev2)).wait().is_err() ||
+            rows.parent().view(0, 2).unwrap().sync(&BufferDevice::from(&dev1)).wait().is_err());
+
+    assert!(handle.try_lock().is_err());
+    drop(rows);
+    drop(row);
+    assert!(handle.try_lock().is_ok());
+
+    // Views reaching past the end of the buffer fail even if counting
+    //   their end overflows
+    let buf = Buffer::from_lock(handle.clone()).unwrap();
+    assert!(buf.view(1, usize::max_value()).is_err());
+    let buf = Buffer::from_lock(handle.clone()).unwrap();
+    assert!(buf.write_range(1, &[0; 0]).wait().is_ok());
+
+    // Writes through a mutable view only leave its range stale elsewhere
+    let buf = Buffer::from_lock(handle.clone()).unwrap();
+    let buf = buf.sync(&BufferDevice::from(&dev1)).wait().unwrap();
+    let mut row = buf.view_mut(2, 2).unwrap();
+    row.native_slice_mut(&dev1).unwrap().copy_from_slice(&[7, 8]);
+    assert!(row.native_slice_mut(&dev2).is_err());
+    let row = row.write_range(1, &[6]).wait().unwrap();
+    assert!(row.write_range(1, &[0, 0]).wait().is_err());
+
+    let buf = Buffer::from_lock(handle.clone()).unwrap();
+    assert!(buf.is_range_latest(&BufferDevice::from(&dev2), &(4..6)));
+    assert!(!buf.is_range_latest(&BufferDevice::from(&dev2), &(2..4)));
+    let (_, vec) = buf.sync_to_vec(&dev2).wait().unwrap();
+    assert_eq!(vec, vec![9, 1, 7, 6, 4, 5]);
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_tensor() {
+    let framework = native::Framework::new();
+    let hardware = framework.default_hardware();
+    let dev = framework.new_device(&hardware).unwrap();
+
+    assert!(Tensor::from_vec(&dev, vec![0u32; 6], vec![4, 2]).is_err());
+
+    let tensor = Tensor::from_vec(&dev, vec![0u32, 1, 2, 3, 4, 5], vec![2, 3]).unwrap();
+    assert_eq!(tensor.strides(), &[3, 1]);
+
+    let tensor = tensor.reshape(vec![3, 2]).unwrap();
+    assert_eq!(tensor.dims(), &[3, 2]);
+    assert_eq!(tensor.strides(), &[2, 1]);
+
+    let tensor = tensor.expand_dims(1).unwrap();
+    assert_eq!(tensor.dims(), &[3, 1, 2]);
+    assert!(tensor.shape().expand_dims(4).is_err());
+    assert!(tensor.shape().reshape(vec![4]).is_err());
+
+    let view = tensor.flatten().share();
+    assert_eq!(view.dims(), &[6]);
+    assert_eq!(view.view().native_slice(&dev).unwrap(), &[0, 1, 2, 3, 4, 5]);
+
+    let view = view.reshape(vec![2, 3]).unwrap();
+    assert_eq!(view.strides(), &[3, 1]);
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_mapped_file() {
+    use std::fs;
+    use std::io::Write;
+
+    let framework = native::Framework::new();
+    let hardware = framework.default_hardware();
+    let dev1 = framework.new_device(&hardware).unwrap();
+    let dev2 = framework.new_device(&hardware).unwrap();
+
+    let path = std::env::temp_dir().join(format!("popcorn-mapped-{}", std::process::id()));
+    let data: Vec<u8> = [1u32, 2, 3, 4].iter().flat_map(|v| v.to_ne_bytes().to_vec()).collect();
+    fs::File::create(&path).unwrap().write_all(&data).unwrap();
+
+    let buf: Buffer<u32> = Buffer::map_file(&dev1, &path, native::MapMode::ReadOnly).unwrap();
+    assert!(buf.native_memory(&dev1).unwrap().is_read_only());
+    assert_eq!(dev1.memory_stats().in_use_bytes, 0);
+    assert!(buf.write_range(0, &[9]).wait().is_err());
+
+    // Writes on another device come back into fresh memory
+    let mut buf: Buffer<u32> = Buffer::map_file(&dev1, &path, native::MapMode::ReadOnly).unwrap();
+    buf = buf.sync(&BufferDevice::from(&dev2)).wait().unwrap();
+    buf.native_memory_mut(&dev2).unwrap().try_as_mut_slice::<u32>().unwrap()[0] = 9;
+    let buf = buf.sync(&BufferDevice::from(&dev1)).wait().unwrap();
+    assert!(!buf.native_memory(&dev1).unwrap().is_mapped());
+    assert_eq!(buf.native_memory(&dev1).unwrap().try_as_slice::<u32>().unwrap(), &[9, 2, 3, 4]);
+
+    let buf: Buffer<u32> = Buffer::map_file(&dev1, &path, native::MapMode::CopyOnWrite).unwrap();
+    let buf = buf.write_range(1, &[7]).wait().unwrap();
+    let (_, vec) = buf.sync_to_vec(&dev1).wait().unwrap();
+    assert_eq!(vec, vec![1, 7, 3, 4]);
+    assert_eq!(fs::read(&path).unwrap(), data);
+
+    assert!(Buffer::<u64>::map_file(&dev1, &path, native::MapMode::ReadOnly).is_ok());
+    fs::write(&path, &data[..6]).unwrap();
+    assert!(Buffer::<u32>::map_file(&dev1, &path, native::MapMode::ReadOnly).is_err());
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_npy() {
+    use std::io::Cursor;
+    use formats::{npy, npz};
+
+    let backend = native::Backend::default();
+    let dev = backend.device();
+
+    let tensor = Tensor::from_vec(dev, vec![1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0], vec![2, 3]).unwrap();
+    let mut file = Vec::new();
+    npy::write(&mut file, tensor.dims(), &[1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0]).unwrap();
+    assert_eq!(&file[..8], b"\x93NUMPY\x01\x00");
+    assert_eq!(file.len() % 64, 24);
+    assert!(String::from_utf8_lossy(&file).contains("{'descr': '<f4', 'fortran_order': False, 'shape': (2, 3), }"));
+
+    let read: Tensor<f32> = npy::read(&mut Cursor::new(&file), dev).unwrap();
+    assert_eq!(read.dims(), &[2, 3]);
+    assert!(npy::read::<_, i32>(&mut Cursor::new(&file), dev).is_err());
+    assert!(npy::read::<_, f32>(&mut Cursor::new(&file[..file.len() - 1]), dev).is_err());
+
+    // Big-endian int16 in Fortran order, as NumPy writes it
+    let header = "{'descr': '>i2', 'fortran_order': True, 'shape': (2, 3), }";
+    let mut file = b"\x93NUMPY\x01\x00".to_vec();
+    file.extend_from_slice(&[header.len() as u8 + 1, 0]);
+    file.extend_from_slice(header.as_bytes());
+    file.push(b'\n');
+    for v in &[1i16, 4, 2, 5, 3, 6] {
+      file.extend_from_slice(&v.to_be_bytes());
+    }
+    let read: Tensor<i16> = npy::read(&mut Cursor::new(&file), dev).unwrap();
+    let (_, vec) = read.sync_to_vec(dev).wait().unwrap();
+    assert_eq!(vec, vec![1, 2, 3, 4, 5, 6]);
+
+    // Headers promising more data than there is fail without allocating
+    //   it, and shapes too large to count fail outright
+    let header_only = |shape: &str| {
+      let header = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': {}, }}", shape);
+      let mut file = b"\x93NUMPY\x01\x00".to_vec();
+      file.extend_from_slice(&[header.len() as u8 + 1, 0]);
+      file.extend_from_slice(header.as_bytes());
+      file.push(b'\n');
+      file.extend_from_slice(&[0; 8]);
+      file
+    };
+    match npy::read::<_, f32>(&mut Cursor::new(header_only("(1099511627776,)")), dev) {
+      Err(formats::Error::SizeMismatch { expected, actual: 8 }) => assert_eq!(expected, 1 << 42),
+      res => panic!("expected a size mismatch, got {:?}", res.map(|t| t.dims().to_vec()))
+    }
+    match npy::read::<_, f32>(&mut Cursor::new(header_only("(4294967296, 4294967296)")), dev) {
+      Err(formats::Error::InvalidHeader(_)) => (),
+      res => panic!("expected an invalid header, got {:?}", res.map(|t| t.dims().to_vec()))
+    }
+
+    // So do header lengths past the cap or the end of the input
+    let header_len = |len: u32| {
+      let mut file = b"\x93NUMPY\x02\x00".to_vec();
+      file.extend_from_slice(&len.to_le_bytes());
+      file.extend_from_slice(&[b' '; 64]);
+      file
+    };
+    match npy::read::<_, f32>(&mut Cursor::new(header_len(u32::max_value())), dev) {
+      Err(formats::Error::InvalidHeader(_)) => (),
+      res => panic!("expected an invalid header, got {:?}", res.map(|t| t.dims().to_vec()))
+    }
+    let file = header_len(1000);
+    match npy::read_sized::<_, f32>(&mut Cursor::new(&file), Some(file.len() as u64), dev) {
+      Err(formats::Error::InvalidHeader(_)) => (),
+      res => panic!("expected an invalid header, got {:?}", res.map(|t| t.dims().to_vec()))
+    }
+
+    let mut writer = npz::NpzWriter::new(Cursor::new(Vec::new())).compressed();
+    writer.add("weights", &tensor, dev).unwrap();
+    writer.write("flags", &[2], &[true, false]).unwrap();
+    let archive = writer.finish().unwrap().into_inner();
+
+    let mut reader = npz::NpzReader::new(Cursor::new(archive)).unwrap();
+    let mut names = reader.names();
+    names.sort();
+    assert_eq!(names, vec!["flags", "weights"]);
+    let weights: Tensor<f32> = reader.read("weights", dev).unwrap();
+    let (_, vec) = weights.sync_to_vec(dev).wait().unwrap();
+    assert_eq!(vec, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+    assert_eq!(reader.header("flags").unwrap().shape, vec![2]);
+    assert!(reader.read::<bool>("missing", dev).is_err());
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_safetensors() {
+    use std::fs;
+    use formats::safetensors::{SafeTensors, SafeTensorsWriter};
+
+    let backend = native::Backend::default();
+    let dev = backend.device();
+    let bdev = BufferDevice::from(dev);
+    let path = std::env::temp_dir().join(format!("popcorn-{}.safetensors", std::process::id()));
+
+    let weights = Tensor::from_vec(dev, vec![1.0f32, 2.0, 3.0, 4.0], vec![2, 2]).unwrap();
+    let mut writer = SafeTensorsWriter::new();
+    writer.metadata("format", "pt");
+    writer.add_tensor("weights", &weights, dev).unwrap();
+    writer.add("ids", &[3], &[7u8, 8, 9]).unwrap();
+    writer.add("bias", &[2], &[0.5f32, 1.5]).unwrap();
+    assert!(writer.add("ids", &[1], &[1u8]).is_err());
+    writer.save(&path).unwrap();
+
+    let file = SafeTensors::open(&path).unwrap();
+    assert_eq!(file.metadata().get("format").map(|s| s.as_str()), Some("pt"));
+    assert_eq!(file.info("bias").unwrap().data_offsets, (19, 27));
+
+    // Aligned data is used in place, the rest is copied
+    let weights: Tensor<f32> = file.tensor("weights", &bdev).unwrap();
+    assert_eq!(weights.dims(), &[2, 2]);
+    assert!(weights.buffer().native_memory(dev).unwrap().is_mapped());
+    let bias: Tensor<f32> = file.tensor("bias", &bdev).unwrap();
+    assert!(!bias.buffer().native_memory(dev).unwrap().is_mapped());
+    let (_, vec) = bias.sync_to_vec(dev).wait().unwrap();
+    assert_eq!(vec, vec![0.5, 1.5]);
+    assert!(file.tensor::<i32>("weights", &bdev).is_err());
+    assert!(file.tensor::<u8>("missing", &bdev).is_err());
+
+    let malformed = |header: &str, data: &[u8]| {
+      let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+      bytes.extend_from_slice(header.as_bytes());
+      bytes.extend_from_slice(data);
+      fs::write(&path, &bytes).unwrap();
+      SafeTensors::open(&path).is_err()
+    };
+    assert!(!malformed(r#"{"a":{"dtype":"U8","shape":[2],"data_offsets":[0,2]}}"#, &[1, 2]));
+    assert!(malformed(r#"{"a":{"dtype":"U8","shape":[2],"data_offsets":[0,2]}}"#, &[1]));
+    assert!(malformed(r#"{"a":{"dtype":"U8","shape":[3],"data_offsets":[0,2]}}"#, &[1, 2]));
+    assert!(malformed(r#"{"a":{"dtype":"X9","shape":[2],"data_offsets":[0,2]}}"#, &[1, 2]));
+    assert!(malformed(r#"{"a":{"dtype":"U8","shape":[2],"data_offsets":[0,2]},
+                          "b":{"dtype":"U8","shape":[2],"data_offsets":[1,3]}}"#, &[1, 2, 3]));
+    assert!(malformed(r#"{"a":"#, &[]));
+    fs::write(&path, &[255u8; 12]).unwrap();
+    assert!(SafeTensors::open(&path).is_err());
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_any_buffer() {
+    use std::io::Cursor;
+    use formats::npy;
+
+    let backend = native::Backend::default();
+    let dev = backend.device();
+
+    let buf: Buffer<i64> = Buffer::from_vec(dev, vec![1, 2, 3]).unwrap();
+    let mut any = AnyBuffer::new(buf);
+    assert_eq!(any.dtype(), DataType::I64);
+    assert_eq!(any.len(), 3);
+    assert!(any.downcast_ref::<f64>().is_err());
+    assert!(any.downcast_mut::<i64>().is_ok());
+
+    let any = match any.downcast::<u64>() {
+      Ok(_) => panic!("downcast to the wrong element type"),
+      Err(any) => any
+    };
+    let buf: Buffer<i64> = any.downcast().unwrap();
+    assert_eq!(buf.len(), 3);
+
+    let mut file = Vec::new();
+    npy::write(&mut file, &[2, 2], &[1u16, 2, 3, 4]).unwrap();
+    let (any, shape) = npy::read_any(&mut Cursor::new(&file), dev).unwrap();
+    assert_eq!(any.dtype(), DataType::U16);
+    assert_eq!(shape, vec![2, 2]);
+    assert_eq!(DataType::from_npy_code("u2"), Some(DataType::U16));
+    assert_eq!(DataType::from_safetensors_name("F64").map(|d| d.size()), Some(8));
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_half() {
+    let backend = native::Backend::default();
+    let dev = backend.device();
+
+    let src: Buffer<f32> = Buffer::from_vec(dev, vec![1.0, 0.5, 65504.0, 1.0e-8]).unwrap();
+    let half: Buffer<f16> = Buffer::new(dev, 4).unwrap();
+    let half = convert::from_f32(dev, src, half).wait().unwrap();
+    assert_eq!(half.native_memory(dev).unwrap().try_as_slice::<f16>().unwrap()[2], f16::MAX);
+
+    let wide: Buffer<f32> = Buffer::new(dev, 4).unwrap();
+    let wide = convert::to_f32(dev, half, wide).wait().unwrap();
+    let (_, vec) = wide.sync_to_vec(dev).wait().unwrap();
+    assert_eq!(vec, vec![1.0, 0.5, 65504.0, 0.0]);
+
+    let src: Buffer<bf16> = Buffer::from_vec(dev, vec![bf16::from_f32(3.0); 2]).unwrap();
+    let wide: Buffer<f32> = Buffer::new(dev, 3).unwrap();
+    assert!(convert::to_f32(dev, src, wide).wait().is_err());
+    assert_eq!(DataType::BF16.npy_code(), None);
+    assert_eq!(DataType::from_safetensors_name("BF16"), Some(DataType::BF16));
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_complex() {
+    use std::io::Cursor;
+    use formats::npy;
+    use formats::safetensors::SafeTensorsWriter;
+
+    let backend = native::Backend::default();
+    let dev = backend.device();
+
+    // Big-endian complex64, where each part is swapped on its own
+    let header = "{'descr': '>c8', 'fortran_order': False, 'shape': (2,), }";
+    let mut file = b"\x93NUMPY\x01\x00".to_vec();
+    file.extend_from_slice(&[header.len() as u8 + 1, 0]);
+    file.extend_from_slice(header.as_bytes());
+    file.push(b'\n');
+    for v in &[1.0f32, -2.0, 3.0, 4.0] {
+      file.extend_from_slice(&v.to_be_bytes());
+    }
+    let read: Tensor<Complex32> = npy::read(&mut Cursor::new(&file), dev).unwrap();
+    let (_, vec) = read.sync_to_vec(dev).wait().unwrap();
+    assert_eq!(vec, vec![Complex32::new(1.0, -2.0), Complex32::new(3.0, 4.0)]);
+
+    assert_eq!(DataType::C128.size(), 16);
+    assert_eq!(DataType::C128.scalar_size(), 8);
+    assert_eq!(DataType::from_npy_code("c16"), Some(DataType::C128));
+
+    let data = [Complex64::new(1.0, 1.0)];
+    let mut writer = SafeTensorsWriter::new();
+    assert!(writer.add("z", &[1], &data).is_err());
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_hardware() {
+    let cpuinfo = "processor\t: 0\nmodel name\t: Test CPU\nphysical id\t: 0\ncore id\t: 0\n\
+                   flags\t\t: fpu sse sse2 pni ssse3 avx avx2\n\n\
+                   processor\t: 1\nphysical id\t: 0\ncore id\t: 0\n\n\
+                   processor\t: 2\nphysical id\t: 0\ncore id\t: 1\n\n";
+    let cpu = native::Hardware::from_cpuinfo(cpuinfo);
+    assert_eq!(cpu.compute_units(), 3);
+    assert_eq!(cpu.physical_units(), 2);
+    assert_eq!(cpu.model_name(), Some("Test CPU"));
+    assert_eq!(cpu.simd(), &[Simd::SSE, Simd::SSE2, Simd::SSE3, Simd::SSSE3, Simd::AVX, Simd::AVX2]);
+    assert!(!cpu.has_simd(Simd::AVX512F));
+
+    let arm = native::Hardware::from_cpuinfo("processor\t: 0\nFeatures\t: fp asimd\n\nprocessor\t: 1\n\n");
+    assert_eq!(arm.physical_units(), 2);
+    assert!(arm.has_simd(Simd::NEON));
+
+    let local = native::Hardware::new();
+    assert!(local.compute_units() >= local.physical_units());
+    assert!(local.physical_units() >= 1);
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_numa_nodes() {
+    use std::fs;
+
+    // Two sockets with two hyperthreaded cores each
+    let root = std::env::temp_dir().join(format!("popcorn-sysfs-{}", std::process::id()));
+    for &(node, cpus) in &[(0, "0-1,4-5"), (1, "2-3,6-7")] {
+      let dir = root.join(format!("devices/system/node/node{}", node));
+      fs::create_dir_all(&dir).unwrap();
+      fs::write(dir.join("cpulist"), format!("{}\n", cpus)).unwrap();
+    }
+    for cpu in 0..8 {
+      let dir = root.join(format!("devices/system/cpu/cpu{}/topology", cpu));
+      fs::create_dir_all(&dir).unwrap();
+      fs::write(dir.join("physical_package_id"), format!("{}", cpu / 2 % 2)).unwrap();
+      fs::write(dir.join("core_id"), format!("{}", cpu % 2)).unwrap();
+    }
+    fs::create_dir_all(root.join("devices/system/node/node2")).unwrap();
+    fs::write(root.join("devices/system/node/node2/cpulist"), "\n").unwrap();
+
+    let framework = native::Framework::with_sysfs_root(&root);
+    let hardwares = framework.load_hardwares().unwrap();
+    fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(hardwares.len(), 2);
+    assert_eq!(hardwares[1].node(), Some(1));
+    assert_eq!(hardwares[1].cpus(), &[2, 3, 6, 7]);
+    assert_eq!(hardwares[1].compute_units(), 4);
+    assert_eq!(hardwares[1].physical_units(), 2);
+
+    // Pinning to CPUs this machine may not have is best effort
+    let device = framework.new_device(&hardwares[1]).unwrap();
+    let buffer: Buffer<f32> = Buffer::from_vec(&device, vec![1.0; 4096]).unwrap();
+    let (_, vec) = buffer.sync_to_vec(&device).wait().unwrap();
+    assert_eq!(vec.len(), 4096);
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_device_builder() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let framework = native::Framework::new();
+    let hardware = framework.default_hardware();
+
+    let started = Arc::new(AtomicUsize::new(0));
+    let stopped = Arc::new(AtomicUsize::new(0));
+    let (s1, s2) = (started.clone(), stopped.clone());
+    let device = framework.device_builder(&hardware).
+      pool_size(2).
+      stack_size(256 * 1024).
+      name_prefix("batch-").
+      affinity(Vec::new()).
+      priority(10).
+      after_start(move || { s1.fetch_add(1, Ordering::SeqCst); }).
+      before_stop(move || { s2.fetch_add(1, Ordering::SeqCst); }).
+      build().unwrap();
+
+    let name = device.pool().spawn_fn(|| {
+      Ok::<_, ()>(std::thread::current().name().map(|name| name.to_string()))
+    }).wait().unwrap();
+    assert!(name.unwrap().starts_with("batch-"));
+
+    // Threads start and stop on their own time
+    drop(device);
+    let wait = |count: &AtomicUsize| {
+      for _ in 0..100 {
+        if count.load(Ordering::SeqCst) == 2 { break }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+      }
+      count.load(Ordering::SeqCst)
+    };
+    assert_eq!(wait(&started), 2);
+    assert_eq!(wait(&stopped), 2);
+
+    assert!(framework.device_builder(&hardware).pool_size(0).build().is_err());
+    assert!(framework.device_builder(&hardware).priority(20).build().is_err());
+  }
+
+  #[test]
+  #[cfg(feature = "sim")]
+  fn test_sim_sync() {
+    use std::time::{Duration, Instant};
+
+    let native = native::Backend::default();
+    let ndev = BufferDevice::from(native.device());
+    let framework = sim::Framework::with_config(sim::Config {
+      latency: Duration::from_millis(5),
+      .. sim::Config::default()
+    });
+    let sdev = framework.default_device();
+    let sdev2 = framework.default_device();
+    let bsdev = BufferDevice::from(&sdev);
+
+    // Uploads copy into the arena, the host copy stays on its own
+    let buf = Buffer::from_vec(native.device(), vec![1.0f32, 2.0, 3.0, 4.0]).unwrap();
+    let start = Instant::now();
+    let mut buf = buf.sync(&bsdev).wait().unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(5));
+    assert_eq!(sdev.memory_used(), 16);
+    assert_eq!(sdev.transfers(), 1);
+
+    buf.native_memory_mut(native.device()).unwrap().try_as_mut_slice::<f32>().unwrap()[0] = 5.0;
+    assert!(!buf.is_latest(&bsdev));
+    let buf = buf.sync(&bsdev).wait().unwrap();
+    let buf = buf.sync(&bsdev).wait().unwrap();
+    assert_eq!(sdev.transfers(), 2);
+
+    // Ranged writes leave the other copy stale in that range only
+    let buf = buf.write_range(2, &[7.0]).wait().unwrap();
+    assert!(buf.is_latest(&bsdev) != buf.is_latest(&ndev));
+    assert!(buf.is_range_latest(&bsdev, &(0..2)) && buf.is_range_latest(&ndev, &(0..2)));
+    let buf = buf.sync(&bsdev).wait().unwrap();
+    let (buf, vec) = buf.sync_to_vec(native.device()).wait().unwrap();
+    assert_eq!(vec, vec![5.0, 2.0, 7.0, 4.0]);
+
+    let (buf, vec) = buf.sync_to_vec(&sdev2).wait().unwrap();
+    assert_eq!(vec, vec![5.0, 2.0, 7.0, 4.0]);
+    assert_eq!(sdev2.memory_used(), 16);
+
+    let kernel = sdev.launch(|| Ok(std::thread::current().name().map(|name| name.to_string())));
+    assert!(kernel.wait().unwrap().unwrap().starts_with("sim-"));
+
+    sdev.fail_next(1);
+    let mut buf = buf;
+    buf.native_memory_mut(native.device()).unwrap().try_as_mut_slice::<f32>().unwrap()[1] = 6.0;
+    let buf = match buf.sync_with(&bsdev, &CancelToken::new()).wait() {
+      Err((buffer::Error::Sim(sim::Error::TransferFailed { bytes: 16 }), buf)) => buf,
+      res => panic!("expected a failed transfer, got {:?}", res.map(|_| ()).map_err(|(err, _)| err))
+    };
+
+    // The failed sync hands the buffer back with the host copy intact and
+    // the device copy still out of date
+    assert!(buf.is_latest(&ndev));
+    assert!(!buf.is_latest(&bsdev));
+    assert_eq!(sdev.memory_used(), 16);
+    let (_, vec) = buf.sync_to_vec(&sdev).wait().unwrap();
+    assert_eq!(vec, vec![5.0, 6.0, 7.0, 4.0]);
+
+    let small = sim::Framework::with_config(sim::Config {
+      capacity: Some(16),
+      .. sim::Config::default()
+    }).default_device();
+    assert!(Buffer::<f32>::new(&small, 4).is_ok());
+    assert!(Buffer::<f32>::new(&small, 5).is_err());
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_streams() {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    let framework = native::Framework::new();
+    let device = framework.default_device();
+    let first = device.new_stream();
+    let second = device.new_stream();
+
+    // Later operations are quicker, they still run after earlier ones
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let ops: Vec<_> = (0..4).map(|i| {
+      let log = log.clone();
+      first.enqueue(move || {
+        std::thread::sleep(Duration::from_millis(20 - i * 5));
+        log.lock().unwrap().push(i);
+        Ok::<_, ()>(i)
+      })
+    }).collect();
+
+    let event = first.record_event();
+    second.wait_event(&event);
+    let l = log.clone();
+    second.enqueue(move || { l.lock().unwrap().push(10); Ok::<_, ()>(()) }).wait().unwrap();
+    assert_eq!(*log.lock().unwrap(), vec![0, 1, 2, 3, 10]);
+    assert_eq!(futures::future::join_all(ops).wait().unwrap(), vec![0, 1, 2, 3]);
+
+    // Failed and panicking operations do not hold up the stream
+    assert!(first.enqueue(|| Err::<(), _>("failed")).wait().is_err());
+    assert_eq!(first.enqueue(|| -> Result<(), ()> { panic!("kernel panicked") }).wait(),
+               Err(stream::Error::Panicked));
+
+    // Device operations run on the pool, the stream waits for them
+    let mem = device.memory_from_vec(vec![1.0f32, 2.0]).unwrap();
+    let d = device.clone();
+    let vec = first.enqueue(move || d.into_vec::<f32>(mem)).wait().unwrap();
+    assert_eq!(vec, vec![1.0, 2.0]);
+    first.synchronize().wait().unwrap();
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_cancel() {
+    let backend = native::Backend::default();
+    let dev = backend.device();
+    let buf: Buffer<f32> = Buffer::from_vec(dev, vec![1.0, 2.0]).unwrap();
+
+    let token = CancelToken::new();
+    token.cancel();
+    let buf = match buf.sync_to_vec_with(dev, &token).wait() {
+      Err((buffer::Error::Cancelled, buf)) => buf,
+      res => panic!("expected cancellation, got {:?}", res.map(|(_, vec)| vec).map_err(|(err, _)| err))
+    };
+
+    // A cancelled operation hands the buffer back as it found it
+    let (_, vec) = buf.sync_to_vec_with(dev, &CancelToken::new()).wait().map_err(|(err, _)| err).unwrap();
+    assert_eq!(vec, vec![1.0, 2.0]);
+
+    // Copies between native memories check the token before every chunk
+    let mut src = native::Memory::alloc(3 * cancel::COPY_CHUNK);
+    src.as_mut_bytes()[0] = 1;
+    let dst = native::Memory::alloc(3 * cancel::COPY_CHUNK);
+    match dev.sync_from_memory_with(dst, src, &token).wait() {
+      Err((native::Error::Cancelled, dst, src)) => {
+        assert_eq!(dst.as_bytes()[0], 0);
+        assert_eq!(src.as_bytes()[0], 1);
+      },
+      res => panic!("expected cancellation, got {:?}", res.map(|_| ()).map_err(|(err, _, _)| err))
+    }
+  }
+
+  #[test]
+  #[cfg(feature = "sim")]
+  fn test_sim_cancel() {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let native = native::Backend::default();
+    let ndev = BufferDevice::from(native.device());
+
+    // Each chunk of the upload takes 100ms, all of it 800ms
+    let framework = sim::Framework::with_config(sim::Config {
+      bandwidth: Some(10 * cancel::COPY_CHUNK),
+      .. sim::Config::default()
+    });
+    let sdev = BufferDevice::from(framework.default_device());
+
+    let len = 2 * cancel::COPY_CHUNK;
+    let buf: Buffer<f32> = Buffer::from_vec(native.device(), vec![1.0; len]).unwrap();
+
+    // Cancelled while the upload is in flight, it stops after the
+    //   current chunk
+    let token = CancelToken::new();
+    let started = Instant::now();
+    let synced = buf.sync_with(&sdev, &token);
+    thread::sleep(Duration::from_millis(20));
+    token.cancel();
+    let buf = match synced.wait() {
+      Err((buffer::Error::Cancelled, buf)) => buf,
+      res => panic!("expected cancellation, got {:?}", res.map(|buf| buf.len()).map_err(|(err, _)| err))
+    };
+    assert!(started.elapsed() < Duration::from_millis(600));
+
+    // The upload is kept but never counts as up to date
+    assert!(buf.has_copy(&sdev));
+    assert!(!buf.is_latest(&sdev));
+    assert!(buf.is_latest(&ndev));
+
+    let (_, vec) = buf.read_range(len - 2..len).wait().unwrap();
+    assert_eq!(vec, vec![1.0, 1.0]);
+  }
+
+  #[test]
+  #[cfg(feature = "native")]
+  fn test_native_profiler() {
+    use std::sync::Arc;
+
+    let collector = Arc::new(Collector::new());
+    let framework = native::Framework::new();
+    let hardware = framework.default_hardware();
+    let device = framework.device_builder(&hardware).
+      profiler(collector.clone()).
+      build().unwrap();
+
+    let buf: Buffer<f32> = Buffer::new(&device, 4).unwrap();
+    let buf = buf.sync_from_vec(vec![1.0, 2.0, 3.0, 4.0], &device).wait().unwrap();
+    let (buf, _) = buf.sync_to_vec(&device).wait().unwrap();
+    let (buf, _) = buf.read_range(1..3).wait().unwrap();
+
+    let spans = collector.spans();
+    let names: Vec<_> = spans.iter().map(|span| span.name).collect();
+    assert_eq!(names, vec!["alloc_memory", "sync_from_vec", "sync_to_vec", "read_range"]);
+    assert!(spans.iter().all(|span| span.framework == "native" && span.device == device.id()));
+
+    let summary = collector.summary();
+    assert_eq!(summary.ops().len(), 4);
+    assert_eq!(summary.get("sync_to_vec").unwrap().bytes, 16);
+    assert_eq!(summary.get("read_range").unwrap().bytes, 8);
+    assert_eq!(summary.get("read_range").unwrap().count, 1);
+    assert!(summary.to_string().contains("sync_from_vec"));
+
+    // Nothing is recorded once the profiler is taken off
+    device.set_profiler(None);
+    buf.sync_to_vec(&device).wait().unwrap();
+    assert_eq!(collector.spans().len(), 4);
+  }
 }
@@ -1,47 +1,416 @@
-use std::sync::Arc;
+use std::cell::UnsafeCell;
+use std::error;
+use std::fmt;
+use std::mem;
+use std::ptr;
+use std::sync::{Arc, Mutex};
 use std::ops::{Deref, DerefMut};
 
-#[derive(Debug, Clone, Copy)]
+use futures::{Async, Future, Poll};
+use futures::task::{self, Task};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Error {
   AlreadyLocked
 }
 
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Error::AlreadyLocked => write!(f, "lock contention: already locked by another holder")
+    }
+  }
+}
+
+impl error::Error for Error { }
+
 pub struct Lock<T> {
-  // Should not use underlying Arc eventually?
-  arc: Arc<T>
+  inner: Arc<Inner<T>>
 }
 
 pub struct LockGuard<T> {
-  arc: Arc<T>
+  inner: Arc<Inner<T>>
+}
+
+pub struct LockFuture<T> {
+  inner: Option<Arc<Inner<T>>>,
+
+  // Slot of the future among the waiters, taken on its first wait
+  waiter: Option<usize>
+}
+
+/// Shared, read-only access to the locked data. Any number of read
+/// guards can be alive at once, but never together with a LockGuard.
+pub struct ReadGuard<T> {
+  inner: Arc<Inner<T>>
+}
+
+pub struct ReadFuture<T> {
+  inner: Option<Arc<Inner<T>>>,
+  waiter: Option<usize>
+}
+
+struct Inner<T> {
+  state: Mutex<State>,
+  data: UnsafeCell<T>
+}
+
+struct State {
+  locked: bool,
+  readers: usize,
+
+  // Tasks waiting for the lock to be released, one slot per future
+  // no matter how often it is polled
+  waiters: Vec<(usize, Task)>,
+  next_waiter: usize
+}
+
+// Access to data is synchronized through state, the same way it is
+// for std::sync::RwLock
+unsafe impl<T: Send> Send for Inner<T> { }
+unsafe impl<T: Send + Sync> Sync for Inner<T> { }
+
+impl State {
+  // Puts the current task in the slot of `waiter`, taking a new slot
+  // on the first wait
+  fn wait(&mut self, waiter: &mut Option<usize>) {
+    let id = match *waiter {
+      Some(id) => id,
+      None => {
+        let id = self.next_waiter;
+        self.next_waiter = self.next_waiter.wrapping_add(1);
+        *waiter = Some(id);
+        id
+      }
+    };
+
+    match self.waiters.iter_mut().find(|waiting| waiting.0 == id) {
+      Some(waiting) => waiting.1 = task::current(),
+      None => self.waiters.push((id, task::current()))
+    }
+  }
+
+  // Frees the slot of a future that stopped waiting
+  fn stop_waiting(&mut self, waiter: &mut Option<usize>) {
+    if let Some(id) = waiter.take() {
+      self.waiters.retain(|waiting| waiting.0 != id);
+    }
+  }
+}
+
+impl<T> Inner<T> {
+  fn try_acquire(&self) -> bool {
+    let mut state = self.state.lock().unwrap();
+
+    if state.locked || state.readers > 0 {
+      false
+    } else {
+      state.locked = true;
+      true
+    }
+  }
+
+  fn acquire_or_wait(&self, waiter: &mut Option<usize>) -> bool {
+    let mut state = self.state.lock().unwrap();
+
+    if state.locked || state.readers > 0 {
+      state.wait(waiter);
+      false
+    } else {
+      state.locked = true;
+      state.stop_waiting(waiter);
+      true
+    }
+  }
+
+  fn try_acquire_read(&self) -> bool {
+    let mut state = self.state.lock().unwrap();
+
+    if state.locked {
+      false
+    } else {
+      state.readers += 1;
+      true
+    }
+  }
+
+  fn acquire_read_or_wait(&self, waiter: &mut Option<usize>) -> bool {
+    let mut state = self.state.lock().unwrap();
+
+    if state.locked {
+      state.wait(waiter);
+      false
+    } else {
+      state.readers += 1;
+      state.stop_waiting(waiter);
+      true
+    }
+  }
+
+  fn stop_waiting(&self, waiter: &mut Option<usize>) {
+    self.state.lock().unwrap().stop_waiting(waiter);
+  }
+
+  fn add_reader(&self) {
+    self.state.lock().unwrap().readers += 1;
+  }
+
+  fn try_upgrade(&self) -> bool {
+    let mut state = self.state.lock().unwrap();
+
+    if state.readers == 1 {
+      state.readers = 0;
+      state.locked = true;
+      true
+    } else {
+      false
+    }
+  }
+
+  fn downgrade(&self) {
+    let waiters = {
+      let mut state = self.state.lock().unwrap();
+      state.locked = false;
+      state.readers += 1;
+      state.waiters.split_off(0)
+    };
+
+    Self::notify(waiters);
+  }
+
+  fn release(&self) {
+    let waiters = {
+      let mut state = self.state.lock().unwrap();
+      state.locked = false;
+      state.waiters.split_off(0)
+    };
+
+    Self::notify(waiters);
+  }
+
+  fn release_read(&self) {
+    let waiters = {
+      let mut state = self.state.lock().unwrap();
+      state.readers -= 1;
+
+      if state.readers == 0 {
+        state.waiters.split_off(0)
+      } else {
+        Vec::new()
+      }
+    };
+
+    Self::notify(waiters);
+  }
+
+  fn notify(waiters: Vec<(usize, Task)>) {
+    // Wake everyone waiting, the first ones polled get the lock and the
+    // rest register themselves again
+    for (_, waiter) in waiters {
+      waiter.notify();
+    }
+  }
 }
 
 impl<T> Lock<T> {
   pub fn new(data: T) -> Lock<T> {
     Lock {
-      arc: Arc::new(data)
+      inner: Arc::new(Inner {
+        state: Mutex::new(State {
+          locked: false,
+          readers: 0,
+          waiters: Vec::new(),
+          next_waiter: 0
+        }),
+        data: UnsafeCell::new(data)
+      })
     }
   }
 
   pub fn try_lock(&self) -> Result<LockGuard<T>, Error> {
-    // TODO: This implementation is incorrect, but will work in most
-    //   cases. This function needs to be synchronized somehow
-
-    if Arc::strong_count(&self.arc) == 1 {
+    if self.inner.try_acquire() {
       Ok(LockGuard {
-        arc: self.arc.clone()
+        inner: self.inner.clone()
       })
     } else {
       Err(Error::AlreadyLocked)
     }
   }
+
+  /// Returns a future that resolves to a guard once the current holder
+  /// releases the lock.
+  pub fn lock(&self) -> LockFuture<T> {
+    LockFuture {
+      inner: Some(self.inner.clone()),
+      waiter: None
+    }
+  }
+
+  pub fn try_read(&self) -> Result<ReadGuard<T>, Error> {
+    if self.inner.try_acquire_read() {
+      Ok(ReadGuard {
+        inner: self.inner.clone()
+      })
+    } else {
+      Err(Error::AlreadyLocked)
+    }
+  }
+
+  /// Returns a future that resolves to a read guard once no LockGuard
+  /// is held anymore.
+  pub fn read(&self) -> ReadFuture<T> {
+    ReadFuture {
+      inner: Some(self.inner.clone()),
+      waiter: None
+    }
+  }
+
+  /// Number of futures waiting for the lock.
+  #[cfg(test)]
+  pub(crate) fn waiting(&self) -> usize {
+    self.inner.state.lock().unwrap().waiters.len()
+  }
+}
+
+impl<T> Clone for Lock<T> {
+  fn clone(&self) -> Lock<T> {
+    Lock {
+      inner: self.inner.clone()
+    }
+  }
+}
+
+impl<T> LockGuard<T> {
+  pub fn handle(&self) -> Lock<T> {
+    Lock {
+      inner: self.inner.clone()
+    }
+  }
+
+  /// Atomically turns exclusive access into shared access, so no writer
+  /// can sneak in between.
+  pub fn downgrade(self) -> ReadGuard<T> {
+    // Move the Arc out without running Drop, which would release the lock
+    let inner = unsafe { ptr::read(&self.inner) };
+    mem::forget(self);
+    inner.downgrade();
+
+    ReadGuard {
+      inner: inner
+    }
+  }
+}
+
+impl<T> ReadGuard<T> {
+  pub fn handle(&self) -> Lock<T> {
+    Lock {
+      inner: self.inner.clone()
+    }
+  }
+
+  /// Turns this guard into exclusive access if it is the only reader
+  /// left, otherwise hands the guard back.
+  pub fn try_upgrade(self) -> Result<LockGuard<T>, ReadGuard<T>> {
+    if self.inner.try_upgrade() {
+      let inner = unsafe { ptr::read(&self.inner) };
+      mem::forget(self);
+
+      Ok(LockGuard {
+        inner: inner
+      })
+    } else {
+      Err(self)
+    }
+  }
+}
+
+impl<T> Clone for ReadGuard<T> {
+  fn clone(&self) -> ReadGuard<T> {
+    self.inner.add_reader();
+
+    ReadGuard {
+      inner: self.inner.clone()
+    }
+  }
+}
+
+impl<T> Future for LockFuture<T> {
+  type Item = LockGuard<T>;
+  type Error = Error;
+
+  fn poll(&mut self) -> Poll<LockGuard<T>, Error> {
+    let acquired = self.inner.as_ref().
+      expect("LockFuture polled after completion").
+      acquire_or_wait(&mut self.waiter);
+
+    if acquired {
+      let inner = self.inner.take().unwrap();
+      Ok(Async::Ready(LockGuard { inner: inner }))
+    } else {
+      Ok(Async::NotReady)
+    }
+  }
+}
+
+impl<T> Future for ReadFuture<T> {
+  type Item = ReadGuard<T>;
+  type Error = Error;
+
+  fn poll(&mut self) -> Poll<ReadGuard<T>, Error> {
+    let acquired = self.inner.as_ref().
+      expect("ReadFuture polled after completion").
+      acquire_read_or_wait(&mut self.waiter);
+
+    if acquired {
+      let inner = self.inner.take().unwrap();
+      Ok(Async::Ready(ReadGuard { inner: inner }))
+    } else {
+      Ok(Async::NotReady)
+    }
+  }
+}
+
+// Futures dropped while waiting give up their slot
+impl<T> Drop for LockFuture<T> {
+  fn drop(&mut self) {
+    if let Some(ref inner) = self.inner {
+      inner.stop_waiting(&mut self.waiter);
+    }
+  }
+}
+
+impl<T> Drop for ReadFuture<T> {
+  fn drop(&mut self) {
+    if let Some(ref inner) = self.inner {
+      inner.stop_waiting(&mut self.waiter);
+    }
+  }
 }
 
 impl<T> Deref for LockGuard<T> {
   type Target = T;
 
-  fn deref(&self) -> &T { &self.arc }
+  fn deref(&self) -> &T { unsafe { &*self.inner.data.get() } }
 }
 
 impl<T> DerefMut for LockGuard<T> {
-  fn deref_mut(&mut self) -> &mut T { Arc::get_mut(&mut self.arc).unwrap() }
+  fn deref_mut(&mut self) -> &mut T { unsafe { &mut *self.inner.data.get() } }
+}
+
+impl<T> Drop for LockGuard<T> {
+  fn drop(&mut self) {
+    self.inner.release();
+  }
+}
+
+impl<T> Deref for ReadGuard<T> {
+  type Target = T;
+
+  fn deref(&self) -> &T { unsafe { &*self.inner.data.get() } }
+}
+
+impl<T> Drop for ReadGuard<T> {
+  fn drop(&mut self) {
+    self.inner.release_read();
+  }
 }
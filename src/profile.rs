@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// One operation on a device, from the moment it was submitted until it
+/// finished.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+  pub name: &'static str,
+
+  // Framework and id of the device the operation ran on
+  pub framework: &'static str,
+  pub device: isize,
+
+  // Bytes read and written by the operation
+  pub bytes: usize,
+
+  // Floating point operations, for kernels that know their count
+  pub flops: Option<u64>,
+
+  // Time between submitting the operation and a thread picking it up
+  pub queue_wait: Duration,
+
+  pub exec_time: Duration
+}
+
+/// Receives a span for every operation of the devices it is set on.
+/// Spans are recorded from the device threads, so implementations
+/// should not block for long.
+pub trait Profiler: Send + Sync {
+  fn record(&self, span: Span);
+}
+
+/// Profiler that keeps every span in memory.
+#[derive(Debug, Default)]
+pub struct Collector {
+  spans: Mutex<Vec<Span>>
+}
+
+/// Totals of all spans sharing a name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpSummary {
+  pub name: &'static str,
+  pub count: usize,
+  pub bytes: usize,
+  pub flops: Option<u64>,
+  pub queue_wait: Duration,
+  pub exec_time: Duration
+}
+
+/// Spans aggregated per operation, the most expensive first. Displays
+/// as a table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+  ops: Vec<OpSummary>
+}
+
+/// Times an operation and hands its span to the profiler once it is
+/// finished or dropped, so operations bailing out early with an error
+/// are recorded too. Without a profiler nothing is recorded.
+pub struct Timer {
+  profiler: Option<Arc<Profiler>>,
+  span: Span,
+  submitted: Instant,
+  started: Option<Instant>
+}
+
+impl Collector {
+  pub fn new() -> Collector {
+    Collector::default()
+  }
+
+  pub fn spans(&self) -> Vec<Span> {
+    self.spans.lock().unwrap().clone()
+  }
+
+  pub fn clear(&self) {
+    self.spans.lock().unwrap().clear();
+  }
+
+  pub fn summary(&self) -> Summary {
+    Summary::new(&self.spans.lock().unwrap())
+  }
+}
+
+impl Profiler for Collector {
+  fn record(&self, span: Span) {
+    self.spans.lock().unwrap().push(span);
+  }
+}
+
+impl Summary {
+  pub fn new(spans: &[Span]) -> Summary {
+    let mut ops: HashMap<&'static str, OpSummary> = HashMap::new();
+    for span in spans {
+      let op = ops.entry(span.name).or_insert(OpSummary {
+        name: span.name,
+        count: 0,
+        bytes: 0,
+        flops: None,
+        queue_wait: Duration::from_secs(0),
+        exec_time: Duration::from_secs(0)
+      });
+
+      op.count += 1;
+      op.bytes += span.bytes;
+      op.queue_wait += span.queue_wait;
+      op.exec_time += span.exec_time;
+      if let Some(flops) = span.flops {
+        op.flops = Some(op.flops.unwrap_or(0) + flops);
+      }
+    }
+
+    let mut ops: Vec<OpSummary> = ops.into_iter().map(|(_, op)| op).collect();
+    ops.sort_by(|a, b| b.exec_time.cmp(&a.exec_time).then(a.name.cmp(b.name)));
+    Summary {
+      ops: ops
+    }
+  }
+
+  pub fn ops(&self) -> &[OpSummary] { &self.ops }
+
+  pub fn get(&self, name: &str) -> Option<&OpSummary> {
+    self.ops.iter().find(|op| op.name == name)
+  }
+
+  pub fn exec_time(&self) -> Duration {
+    self.ops.iter().map(|op| op.exec_time).sum()
+  }
+}
+
+impl fmt::Display for Summary {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    try!(writeln!(f, "{:<24} {:>8} {:>14} {:>14} {:>14} {:>14}",
+                  "operation", "count", "bytes", "flops", "queue wait", "exec time"));
+    for op in &self.ops {
+      let flops = op.flops.map(|flops| flops.to_string()).unwrap_or("-".to_string());
+      try!(writeln!(f, "{:<24} {:>8} {:>14} {:>14} {:>14} {:>14}",
+                    op.name, op.count, op.bytes, flops,
+                    format!("{:.3}ms", millis(op.queue_wait)),
+                    format!("{:.3}ms", millis(op.exec_time))));
+    }
+    Ok(())
+  }
+}
+
+fn millis(duration: Duration) -> f64 {
+  duration.as_secs_f64() * 1000.0
+}
+
+impl Timer {
+  /// Starts waiting in the queue right away.
+  pub fn new(profiler: Option<Arc<Profiler>>,
+             name: &'static str,
+             framework: &'static str,
+             device: isize,
+             bytes: usize) -> Timer {
+    Timer {
+      profiler: profiler,
+      span: Span {
+        name: name,
+        framework: framework,
+        device: device,
+        bytes: bytes,
+        flops: None,
+        queue_wait: Duration::from_secs(0),
+        exec_time: Duration::from_secs(0)
+      },
+      submitted: Instant::now(),
+      started: None
+    }
+  }
+
+  pub fn set_bytes(&mut self, bytes: usize) {
+    self.span.bytes = bytes;
+  }
+
+  pub fn set_flops(&mut self, flops: u64) {
+    self.span.flops = Some(flops);
+  }
+
+  /// Ends the queue wait, the operation is executing from now on.
+  pub fn start(&mut self) {
+    self.started = Some(Instant::now());
+  }
+
+  /// Records the span right away instead of when the timer is dropped.
+  pub fn finish(self) { }
+}
+
+// Operations that were never started count as executing since they were
+// submitted
+impl Drop for Timer {
+  fn drop(&mut self) {
+    if let Some(profiler) = self.profiler.take() {
+      let started = self.started.unwrap_or(self.submitted);
+      let mut span = self.span.clone();
+      span.queue_wait = started.duration_since(self.submitted);
+      span.exec_time = started.elapsed();
+      profiler.record(span);
+    }
+  }
+}
+
+impl fmt::Debug for Timer {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Timer {{ span: {:?} }}", &self.span)
+  }
+}
@@ -0,0 +1,170 @@
+use std::error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use futures::{Future, IntoFuture};
+use futures::future::Shared;
+use futures::sync::oneshot;
+
+static NEXT_STREAM_ID: AtomicUsize = AtomicUsize::new(0);
+
+type Job = Box<FnOnce() + Send>;
+
+/// Error of an operation enqueued on a stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error<E> {
+  // Operation failed with its own error
+  Operation(E),
+
+  // Operation panicked, the stream carried on with the next one
+  Panicked
+}
+
+/// Queue of operations on a device, like a CUDA stream or an OpenCL
+/// command queue. Operations enqueued on one stream run one after the
+/// other in the order they were enqueued, different streams run side
+/// by side. Clones enqueue on the same stream.
+///
+/// Every stream has a thread of its own that only waits for its
+/// operations, the work itself runs on the device pool. These threads
+/// are not part of the pool, so they are neither counted in its size
+/// nor pinned or prioritized like the device builder sets up the pool
+/// threads. Each one sleeps while its stream is idle and stops once the
+/// stream is dropped, but streams are best created once and reused.
+#[derive(Clone)]
+pub struct Stream {
+  id: usize,
+  sender: Sender<Job>
+}
+
+/// Point in a stream, complete once every operation enqueued on the
+/// stream before it is done.
+#[derive(Clone)]
+pub struct Event {
+  signal: Shared<oneshot::Receiver<()>>
+}
+
+impl Stream {
+  /// Stream with its own thread, named `name_prefix` followed by the
+  /// stream id. The thread stops once every clone is dropped and the
+  /// queue is drained.
+  pub fn new(name_prefix: &str) -> Stream {
+    let id = NEXT_STREAM_ID.fetch_add(1, Ordering::SeqCst);
+    let (sender, receiver) = mpsc::channel::<Job>();
+
+    thread::Builder::new().
+      name(format!("{}{}", name_prefix, id)).
+      spawn(move || {
+        for job in receiver {
+          // A panicking operation fails its own future, the stream
+          //   carries on with the next one
+          let _ = panic::catch_unwind(AssertUnwindSafe(job));
+        }
+      }).
+      expect("failed to spawn stream thread");
+
+    Stream {
+      id: id,
+      sender: sender
+    }
+  }
+
+  pub fn id(&self) -> usize { self.id }
+
+  /// Calls `f` once every operation enqueued before it is done and waits
+  /// for the future it returns before moving on, whether that fails,
+  /// panics or not. `f` runs on the stream thread and should only start
+  /// its work, through device operations or the device pool. The
+  /// operation runs even if the returned future is dropped.
+  pub fn enqueue<F, U>(&self, f: F) -> Box<Future<Item=U::Item,Error=Error<U::Error>> + Send>
+    where F: FnOnce() -> U + Send + 'static,
+          U: IntoFuture + 'static,
+          U::Item: Send + 'static,
+          U::Error: Send + 'static {
+    let (sender, receiver) = oneshot::channel();
+    self.push(Box::new(move || {
+      let result = match panic::catch_unwind(AssertUnwindSafe(|| f().into_future().wait())) {
+        Ok(result) => result.map_err(Error::Operation),
+        Err(_) => Err(Error::Panicked)
+      };
+      let _ = sender.send(result);
+    }));
+
+    // The sender only goes away without a result if the job unwound
+    Box::new(receiver.then(|result| match result {
+      Ok(result) => result,
+      Err(_) => Err(Error::Panicked)
+    }))
+  }
+
+  /// Event that completes once everything enqueued so far is done.
+  pub fn record_event(&self) -> Event {
+    let (sender, receiver) = oneshot::channel();
+    self.push(Box::new(move || {
+      let _ = sender.send(());
+    }));
+
+    Event {
+      signal: receiver.shared()
+    }
+  }
+
+  /// Holds back operations enqueued from now on until `event` is
+  /// complete, which may be recorded on another stream.
+  pub fn wait_event(&self, event: &Event) {
+    let event = event.clone();
+    self.push(Box::new(move || {
+      let _ = event.wait().wait();
+    }));
+  }
+
+  /// Future that resolves once everything enqueued so far is done.
+  pub fn synchronize(&self) -> Box<Future<Item=(),Error=()> + Send> {
+    self.record_event().wait()
+  }
+
+  fn push(&self, job: Job) {
+    // The thread only stops once every sender is gone
+    self.sender.send(job).unwrap();
+  }
+}
+
+impl Event {
+  /// Future that resolves once the event is complete, it never fails.
+  pub fn wait(&self) -> Box<Future<Item=(),Error=()> + Send> {
+    Box::new(self.signal.clone().then(|_| Ok(())))
+  }
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Error::Operation(ref err) => write!(f, "stream operation failed: {}", err),
+      Error::Panicked => write!(f, "stream operation panicked")
+    }
+  }
+}
+
+impl<E: error::Error + 'static> error::Error for Error<E> {
+  fn source(&self) -> Option<&(error::Error + 'static)> {
+    match *self {
+      Error::Operation(ref err) => Some(err),
+      Error::Panicked => None
+    }
+  }
+}
+
+impl fmt::Debug for Stream {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Stream {{ id: {} }}", self.id)
+  }
+}
+
+impl fmt::Debug for Event {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Event")
+  }
+}
@@ -0,0 +1,223 @@
+use futures::Future;
+
+use buffer::{Buffer, BufferView, BufferDevice, ReadBuffer, Error};
+
+/// Dimensions and row-major strides of a tensor, both in elements.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shape {
+  dims: Vec<usize>,
+  strides: Vec<usize>
+}
+
+/// Buffer bundled with the shape of its contents. The shape lives on the
+/// host, so reshaping never touches the data.
+pub struct Tensor<T: Copy + Sized + Send + 'static> {
+  buffer: Buffer<T>,
+  shape: Shape
+}
+
+/// Shared, read-only tensor backed by a view of a buffer.
+pub struct TensorView<T: Copy + Sized + Send + 'static> {
+  view: BufferView<T>,
+  shape: Shape
+}
+
+impl Shape {
+  pub fn new(dims: Vec<usize>) -> Shape {
+    let mut strides = vec![1; dims.len()];
+    for i in (0..dims.len().saturating_sub(1)).rev() {
+      strides[i] = strides[i + 1] * dims[i + 1];
+    }
+
+    Shape {
+      dims: dims,
+      strides: strides
+    }
+  }
+
+  pub fn dims(&self) -> &[usize] { &self.dims }
+
+  pub fn strides(&self) -> &[usize] { &self.strides }
+
+  pub fn ndim(&self) -> usize { self.dims.len() }
+
+  pub fn len(&self) -> usize { self.dims.iter().product() }
+
+  pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+  fn check_len(&self, len: usize) -> Result<(), Error> {
+    if self.len() != len {
+      return Err(Error::InvalidShape {
+        shape: self.dims.clone(),
+        len: len
+      })
+    }
+
+    Ok(())
+  }
+
+  pub fn reshape(&self, dims: Vec<usize>) -> Result<Shape, Error> {
+    let shape = Shape::new(dims);
+    try!(shape.check_len(self.len()));
+    Ok(shape)
+  }
+
+  pub fn flatten(&self) -> Shape {
+    Shape::new(vec![self.len()])
+  }
+
+  pub fn expand_dims(&self, axis: usize) -> Result<Shape, Error> {
+    if axis > self.ndim() {
+      return Err(Error::InvalidAxis {
+        axis: axis,
+        ndim: self.ndim()
+      })
+    }
+
+    let mut dims = self.dims.clone();
+    dims.insert(axis, 1);
+    Ok(Shape::new(dims))
+  }
+}
+
+impl<T: Send + Copy + Sized + 'static> Tensor<T> {
+  /// Fails unless `dims` holds exactly as many elements as `buffer`.
+  pub fn new(buffer: Buffer<T>, dims: Vec<usize>) -> Result<Tensor<T>, Error> {
+    let shape = Shape::new(dims);
+    try!(shape.check_len(buffer.len()));
+
+    Ok(Tensor {
+      buffer: buffer,
+      shape: shape
+    })
+  }
+
+  pub fn from_vec<D: Into<BufferDevice>>(dev: D, vec: Vec<T>, dims: Vec<usize>) -> Result<Tensor<T>, Error> {
+    let buffer = try!(Buffer::from_vec(dev, vec));
+    Tensor::new(buffer, dims)
+  }
+
+  pub fn shape(&self) -> &Shape { &self.shape }
+
+  pub fn dims(&self) -> &[usize] { self.shape.dims() }
+
+  pub fn strides(&self) -> &[usize] { self.shape.strides() }
+
+  pub fn ndim(&self) -> usize { self.shape.ndim() }
+
+  pub fn buffer(&self) -> &Buffer<T> { &self.buffer }
+
+  pub fn buffer_mut(&mut self) -> &mut Buffer<T> { &mut self.buffer }
+
+  pub fn into_buffer(self) -> Buffer<T> { self.buffer }
+
+  pub fn reshape(mut self, dims: Vec<usize>) -> Result<Tensor<T>, Error> {
+    self.shape = try!(self.shape.reshape(dims));
+    Ok(self)
+  }
+
+  pub fn flatten(mut self) -> Tensor<T> {
+    self.shape = self.shape.flatten();
+    self
+  }
+
+  pub fn expand_dims(mut self, axis: usize) -> Result<Tensor<T>, Error> {
+    self.shape = try!(self.shape.expand_dims(axis));
+    Ok(self)
+  }
+
+  /// Gives up exclusive access so the tensor can be read by several
+  /// operations at once.
+  pub fn share(self) -> TensorView<T> {
+    TensorView {
+      view: self.buffer.share().into(),
+      shape: self.shape
+    }
+  }
+
+  pub fn sync(self, dev: &BufferDevice) -> Box<Future<Item=Tensor<T>,Error=Error>> {
+    let shape = self.shape;
+    Box::new(self.buffer.sync(dev).map(move |buffer| {
+      Tensor {
+        buffer: buffer,
+        shape: shape
+      }
+    }))
+  }
+
+  pub fn sync_to_vec<D: Into<BufferDevice>>(self, dev: D) -> Box<Future<Item=(Tensor<T>, Vec<T>),Error=Error>> {
+    let shape = self.shape;
+    Box::new(self.buffer.sync_to_vec(dev).map(move |(buffer, vec)| {
+      (Tensor { buffer: buffer, shape: shape }, vec)
+    }))
+  }
+}
+
+impl<T: Send + Copy + Sized + 'static> Clone for TensorView<T> {
+  fn clone(&self) -> TensorView<T> {
+    TensorView {
+      view: self.view.clone(),
+      shape: self.shape.clone()
+    }
+  }
+}
+
+impl<T: Send + Copy + Sized + 'static> From<Tensor<T>> for TensorView<T> {
+  fn from(tensor: Tensor<T>) -> TensorView<T> { tensor.share() }
+}
+
+impl<T: Send + Copy + Sized + 'static> TensorView<T> {
+  pub fn new<V: Into<BufferView<T>>>(view: V, dims: Vec<usize>) -> Result<TensorView<T>, Error> {
+    let view = view.into();
+    let shape = Shape::new(dims);
+    try!(shape.check_len(view.len()));
+
+    Ok(TensorView {
+      view: view,
+      shape: shape
+    })
+  }
+
+  pub fn shape(&self) -> &Shape { &self.shape }
+
+  pub fn dims(&self) -> &[usize] { self.shape.dims() }
+
+  pub fn strides(&self) -> &[usize] { self.shape.strides() }
+
+  pub fn ndim(&self) -> usize { self.shape.ndim() }
+
+  pub fn view(&self) -> &BufferView<T> { &self.view }
+
+  pub fn parent(&self) -> &ReadBuffer<T> { self.view.parent() }
+
+  pub fn reshape(&self, dims: Vec<usize>) -> Result<TensorView<T>, Error> {
+    Ok(TensorView {
+      view: self.view.clone(),
+      shape: try!(self.shape.reshape(dims))
+    })
+  }
+
+  pub fn flatten(&self) -> TensorView<T> {
+    TensorView {
+      view: self.view.clone(),
+      shape: self.shape.flatten()
+    }
+  }
+
+  pub fn expand_dims(&self, axis: usize) -> Result<TensorView<T>, Error> {
+    Ok(TensorView {
+      view: self.view.clone(),
+      shape: try!(self.shape.expand_dims(axis))
+    })
+  }
+
+  pub fn sync(self, dev: &BufferDevice) -> Box<Future<Item=TensorView<T>,Error=Error>> {
+    let shape = self.shape;
+    Box::new(self.view.sync(dev).map(move |view| {
+      TensorView {
+        view: view,
+        shape: shape
+      }
+    }))
+  }
+}